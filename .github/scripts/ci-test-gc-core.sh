@@ -28,4 +28,8 @@ do
     fi
 done
 
-
+# run the tests of the binding
+echo "binding"
+$JULIA_PATH/julia $JULIA_TEST_ARGS $BINDING_PATH/.github/scripts/test_ephemeron.jl
+# shut down the GC with the mutators of all the threads bound. This hangs if a GC thread is not stopped.
+timeout 300 $JULIA_PATH/julia $JULIA_TEST_ARGS -t 4 $BINDING_PATH/.github/scripts/test_shutdown.jl
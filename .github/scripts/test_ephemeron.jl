@@ -0,0 +1,37 @@
+# An ephemeron keeps its value alive only while its key is reachable from elsewhere.
+using Test
+
+ccall(:jl_mmtk_init_ephemeron_type, Cvoid, ())
+new_ephemeron(key, value) = ccall(:jl_mmtk_new_ephemeron, Any, (Any, Any), key, value)
+
+mutable struct Box
+    x::Int
+end
+
+# The key is only reachable from the value of the ephemeron.
+@noinline function new_self_ephemeron()
+    key = Box(3)
+    return new_ephemeron(key, Ref{Any}(key))
+end
+
+@testset "Ephemeron" begin
+    @test isdefined(Core, :Ephemeron)
+    @test Core.Ephemeron isa DataType
+
+    # The key is reachable, so the value is kept alive, though only the ephemeron refers to it.
+    key = Box(1)
+    e1 = new_ephemeron(key, Box(2))
+    @test e1 isa Core.Ephemeron
+    GC.gc()
+    @test e1.key === key
+    @test e1.value.x == 2
+
+    # The value does not keep the key alive, so both are cleared.
+    e2 = new_self_ephemeron()
+    GC.gc()
+    @test e2.key === nothing
+    @test e2.value === nothing
+
+    # Keep the key of e1 alive until here.
+    @test key.x == 1
+end
@@ -0,0 +1,31 @@
+# mmtk_gc_shutdown stops the GC while the mutators of all the threads are still bound, and destroys
+# them. No Julia code can run after the shutdown, so we check the result without allocating, and
+# exit with _exit, which skips the exit hooks.
+using Test
+
+struct MutatorSnapshot
+    count::Csize_t
+    tls::Ptr{Cvoid}
+end
+
+bound_mutators() = ccall(:mmtk_mutator_snapshot, MutatorSnapshot, ()).count
+
+@testset "Shutdown" begin
+    # Allocate on every thread, so each of them has a bound mutator.
+    @test Threads.nthreads() > 1
+    Threads.@threads :static for i in 1:Threads.nthreads()
+        sum(length, [Vector{Int}(undef, 64) for _ in 1:1000])
+    end
+    GC.gc()
+    @test bound_mutators() >= Threads.nthreads()
+end
+
+function shutdown_and_exit()
+    ccall(:mmtk_gc_shutdown, Cvoid, ())
+    # A second call does nothing.
+    ccall(:mmtk_gc_shutdown, Cvoid, ())
+    status = bound_mutators() == 0 ? 0 : 1
+    ccall(:_exit, Cvoid, (Cint,), status)
+end
+
+shutdown_and_exit()
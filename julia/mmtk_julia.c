@@ -18,16 +18,12 @@ extern void gc_premark(jl_ptls_t ptls2);
 extern uint64_t finalizer_rngState[JL_RNG_SIZE];
 extern const unsigned pool_sizes[];
 extern void mmtk_store_obj_size_c(void* obj, size_t size);
-extern void jl_gc_free_array(jl_array_t *a);
 extern size_t mmtk_get_obj_size(void* obj);
 extern void jl_rng_split(uint64_t to[JL_RNG_SIZE], uint64_t from[JL_RNG_SIZE]);
 extern jl_mutex_t finalizers_lock;
 extern void jl_gc_wait_for_the_world(jl_ptls_t* gc_all_tls_states, int gc_n_threads);
 extern void mmtk_block_thread_for_gc(void);
 
-extern void* new_mutator_iterator(void);
-extern jl_ptls_t get_next_mutator_tls(void*);
-extern void* close_mutator_iterator(void*);
 
 JL_DLLEXPORT void (jl_mmtk_harness_begin)(void)
 {
@@ -223,36 +219,50 @@ JL_DLLEXPORT jl_value_t *jl_mmtk_gc_alloc_big(jl_ptls_t ptls, size_t sz)
     return result;
 }
 
-static void mmtk_sweep_malloced_arrays(void) JL_NOTSAFEPOINT
+// Allocate the data buffer of an array in MMTk's large object space rather than with malloc, if
+// `mmtk_los_array_buffers_enabled()`. The array should use the buffer with `how == 1`, so the
+// buffer is reached from the array's data pointer, and it is not tracked in `mallocarrays`.
+JL_DLLEXPORT void *jl_mmtk_alloc_array_buffer(jl_ptls_t ptls, size_t sz)
+{
+    static_assert(offsetof(bigval_t, header) >= offsetof(bigval_t, sz) + sizeof(size_t) + sizeof(void*),
+                  "No padding before the bigval header for the buffer size");
+    size_t osize = sz + sizeof(jl_taggedvalue_t);
+    jl_value_t *buf = jl_mmtk_gc_alloc_big(ptls, osize);
+    jl_set_typeof(buf, (void*)jl_buff_tag);
+    // like the other buffer objects, store the size in the word before the tag (the padding of
+    // bigval_t), as the binding reads it there for objects tagged with jl_buff_tag
+    mmtk_store_obj_size_c(buf, osize + sizeof(jl_taggedvalue_t));
+    return buf;
+}
+
+// The malloc'd arrays are swept in Rust (see julia_malloced_arrays.rs).
+static void mmtk_sweep_sysimg(void) JL_NOTSAFEPOINT
 {
-    void* iter = new_mutator_iterator();
-    jl_ptls_t ptls2 = get_next_mutator_tls(iter);
-    while(ptls2 != NULL) {
-        mallocarray_t *ma = ptls2->heap.mallocarrays;
-        mallocarray_t **pma = &ptls2->heap.mallocarrays;
-        while (ma != NULL) {
-            mallocarray_t *nxt = ma->next;
-            if (!mmtk_object_is_managed_by_mmtk(ma->a)) {
-                pma = &ma->next;
-                ma = nxt;
-                continue;
-            }
-            if (mmtk_is_live_object(ma->a)) {
-                pma = &ma->next;
-            }
-            else {
-                *pma = nxt;
-                assert(ma->a->flags.how == 2);
-                jl_gc_free_array(ma->a);
-                ma->next = ptls2->heap.mafreelist;
-                ptls2->heap.mafreelist = ma;
-            }
-            ma = nxt;
-        }
-        ptls2 = get_next_mutator_tls(iter);
-    }
     gc_sweep_sysimg();
-    close_mutator_iterator(iter);
+}
+
+// Free the buffer of a dead malloc'd array, like jl_gc_free_array(). The binding frees arrays from
+// several GC threads at once (see julia_malloced_arrays.rs). free() is thread safe, and the only
+// shared state is the global counters in gc_num, which we update atomically.
+static void mmtk_free_array(void *a_raw) JL_NOTSAFEPOINT
+{
+    jl_array_t *a = (jl_array_t*)a_raw;
+    if (a->flags.how == 2) {
+        char *d = (char*)a->data - a->offset*a->elsize;
+#ifdef _OS_WINDOWS_
+        if (a->flags.isaligned)
+            _aligned_free(d);
+        else
+            free(d);
+#else
+        // jl_malloc_aligned() uses posix_memalign(), so aligned buffers are freed with free() too
+        free(d);
+#endif
+        static_assert(sizeof(_Atomic(int64_t)) == sizeof(gc_num.freed), "");
+        jl_atomic_fetch_add((_Atomic(int64_t)*)&gc_num.freed, jl_array_nbytes(a));
+        static_assert(sizeof(_Atomic(uint64_t)) == sizeof(gc_num.freecall), "");
+        jl_atomic_fetch_add((_Atomic(uint64_t)*)&gc_num.freecall, 1);
+    }
 }
 
 void mmtk_wait_in_a_safepoint(void) {
@@ -265,12 +275,37 @@ void mmtk_exit_from_safepoint(int8_t old_state) {
     jl_gc_state_set(ptls, old_state, JL_GC_STATE_WAITING);
 }
 
+// Map the reason for a GC in MMTk to the collection kind that Julia uses in its probes.
+static jl_gc_collection_t mmtk_gc_reason_to_collection(int reason)
+{
+    switch (reason) {
+    case MMTK_GC_REASON_USER_FULL:
+    case MMTK_GC_REASON_EMERGENCY:
+        return JL_GC_FULL;
+    case MMTK_GC_REASON_USER_INCREMENTAL:
+        return JL_GC_INCREMENTAL;
+    default:
+        return JL_GC_AUTO;
+    }
+}
+
+extern void run_finalizers(jl_task_t *ct);
+
+// Run the finalizers of dead objects: those in to_finalize and, with julia_native_finalizers, the
+// ones that the binding queued (see queue_ready_finalizers in julia_finalizer.rs).
+static void mmtk_run_pending_finalizers(jl_task_t *ct)
+{
+    run_finalizers(ct);
+    if (mmtk_native_finalizers_enabled())
+        mmtk_run_finalizers(false);
+}
+
 // based on jl_gc_collect from gc.c
-JL_DLLEXPORT void jl_gc_prepare_to_collect(void)
+// Returns false if collection is disabled and this thread did not block for the GC. Then the binding
+// defers the GC until collection is enabled again.
+JL_DLLEXPORT bool jl_gc_prepare_to_collect(int reason)
 {
-    // FIXME: set to JL_GC_AUTO since we're calling it from mmtk
-    // maybe just remove this?
-    JL_PROBE_GC_BEGIN(JL_GC_AUTO);
+    JL_PROBE_GC_BEGIN(mmtk_gc_reason_to_collection(reason));
 
     jl_task_t *ct = jl_current_task;
     jl_ptls_t ptls = ct->ptls;
@@ -279,7 +314,7 @@ JL_DLLEXPORT void jl_gc_prepare_to_collect(void)
         jl_atomic_store_relaxed(&ptls->gc_num.allocd, -(int64_t)gc_num.interval);
         static_assert(sizeof(_Atomic(uint64_t)) == sizeof(gc_num.deferred_alloc), "");
         jl_atomic_fetch_add((_Atomic(uint64_t)*)&gc_num.deferred_alloc, localbytes);
-        return;
+        return false;
     }
 
     int8_t old_state = jl_atomic_load_relaxed(&ptls->gc_state);
@@ -289,7 +324,7 @@ JL_DLLEXPORT void jl_gc_prepare_to_collect(void)
     if (!jl_safepoint_start_gc()) {
         // either another thread is running GC, or the GC got disabled just now.
         jl_gc_state_set(ptls, old_state, JL_GC_STATE_WAITING);
-        return;
+        return true;
     }
 
     JL_TIMING_SUSPEND_TASK(GC, ct);
@@ -321,7 +356,9 @@ JL_DLLEXPORT void jl_gc_prepare_to_collect(void)
     gc_num.time_to_safepoint = duration;
     gc_num.total_time_to_safepoint += duration;
 
+    bool collected = false;
     if (!jl_atomic_load_acquire(&jl_gc_disable_counter)) {
+        collected = true;
         JL_LOCK_NOGC(&finalizers_lock); // all the other threads are stopped, so this does not make sense, right? otherwise, failing that, this seems like plausibly a deadlock
 #ifndef __clang_gcanalyzer__
         mmtk_block_thread_for_gc();
@@ -338,9 +375,10 @@ JL_DLLEXPORT void jl_gc_prepare_to_collect(void)
     // Only disable finalizers on current thread
     // Doing this on all threads is racy (it's impossible to check
     // or wait for finalizers on other threads without dead lock).
-    if (!ptls->finalizers_inhibited && ptls->locks.len == 0) {
+    // If the finalizer thread is running, it runs the finalizers instead.
+    if (!ptls->finalizers_inhibited && ptls->locks.len == 0 && !mmtk_finalizer_thread_running()) {
         JL_TIMING(GC, GC_Finalizers);
-        run_finalizers(ct);
+        mmtk_run_pending_finalizers(ct);
     }
     JL_PROBE_GC_FINALIZER();
 
@@ -348,19 +386,63 @@ JL_DLLEXPORT void jl_gc_prepare_to_collect(void)
     SetLastError(last_error);
 #endif
     errno = last_errno;
+    return collected;
 }
 
-extern void run_finalizers(jl_task_t *ct);
-
 // Called after GC to run finalizers
 void mmtk_jl_run_finalizers(void* ptls_raw) {
     jl_ptls_t ptls = (jl_ptls_t) ptls_raw;
-    if (!ptls->finalizers_inhibited && ptls->locks.len == 0) {
+    if (!ptls->finalizers_inhibited && ptls->locks.len == 0 && !mmtk_finalizer_thread_running()) {
         JL_TIMING(GC, GC_Finalizers);
-        run_finalizers(jl_current_task);
+        mmtk_run_pending_finalizers(jl_current_task);
+    }
+}
+
+// Run a finalizer that was registered with mmtk_register_finalizer. run_finalizer expects the
+// object to be tagged with 1 if the finalizer is a C function, as in the finalizer lists. The
+// object is no longer in any queue, so we root it (and a Julia finalizer) while the finalizer runs.
+void run_finalizer_function(void* obj, void* function, bool is_ptr) {
+    jl_value_t *o = (jl_value_t*)obj;
+    jl_value_t *f = is_ptr ? NULL : (jl_value_t*)function;
+    JL_GC_PUSH2(&o, &f);
+    if (is_ptr) {
+        run_finalizer(jl_current_task, (void*)((uintptr_t)o | 1), function);
+    } else {
+        run_finalizer(jl_current_task, o, f);
+    }
+    JL_GC_POP();
+}
+
+// The main loop of the finalizer thread (see finalizer_thread.rs). The thread is adopted by Julia,
+// so it can run finalizers. It waits for work in a GC safe region, so it does not hold up a GC.
+void mmtk_run_finalizer_thread(void) {
+    jl_adopt_thread();
+    jl_task_t *ct = jl_current_task;
+    jl_ptls_t ptls = ct->ptls;
+    while (1) {
+        int8_t old_state = jl_gc_safe_enter(ptls);
+        bool has_work = mmtk_finalizer_thread_wait_for_work();
+        jl_gc_safe_leave(ptls, old_state);
+        if (!has_work)
+            break;
+        mmtk_run_pending_finalizers(ct);
+        mmtk_finalizer_thread_done();
     }
 }
 
+// Wait for the finalizer thread to run the pending finalizers, or run them on this thread if the
+// finalizer thread is not running.
+JL_DLLEXPORT void jl_mmtk_wait_for_finalizers(void)
+{
+    jl_task_t *ct = jl_current_task;
+    jl_ptls_t ptls = ct->ptls;
+    int8_t old_state = jl_gc_safe_enter(ptls);
+    bool waited = mmtk_wait_for_finalizers();
+    jl_gc_safe_leave(ptls, old_state);
+    if (!waited)
+        mmtk_run_pending_finalizers(ct);
+}
+
 // We implement finalization in the binding side. These functions
 // returns some pointers so MMTk can manipulate finalizer lists.
 
@@ -381,10 +463,93 @@ void* get_marked_finalizers_list(void) {
     return (void*)&finalizer_list_marked;
 }
 
+// Finalizers left in the lists of threads that have exited. MMTk moves them here when a mutator is
+// destroyed, and sweeps the list like a thread local list. It is only accessed with finalizers_lock
+// held, which the thread that runs the GC holds as well.
+static arraylist_t orphan_finalizers;
+static int orphan_finalizers_initialized = 0;
+
+void* get_orphan_finalizers_list(void) {
+    if (!orphan_finalizers_initialized) {
+        arraylist_new(&orphan_finalizers, 0);
+        orphan_finalizers_initialized = 1;
+    }
+    return (void*)&orphan_finalizers;
+}
+
+// MMTk takes finalizers_lock when it moves the finalizers of a thread that exits to the orphan list.
+void mmtk_lock_finalizers(void) {
+    JL_LOCK_NOGC(&finalizers_lock);
+}
+
+void mmtk_unlock_finalizers(void) {
+    JL_UNLOCK_NOGC(&finalizers_lock);
+}
+
+void* get_current_ptls(void) {
+    return (void*)jl_current_task->ptls;
+}
+
 int* get_jl_gc_have_pending_finalizers(void) {
     return (int*)&jl_gc_have_pending_finalizers;
 }
 
+// `mutable struct Ephemeron; key; value; end`. MMTk does not keep the key of an ephemeron alive,
+// and only keeps the value alive while the key is reachable from elsewhere (see weak_refs.rs).
+static jl_datatype_t *jl_mmtk_ephemeron_type = NULL;
+
+// Create the Ephemeron type, and bind it as `Core.Ephemeron`, which also keeps it alive. This should
+// be called once Core is set up, before any ephemeron is created.
+JL_DLLEXPORT void jl_mmtk_init_ephemeron_type(void)
+{
+    if (jl_mmtk_ephemeron_type != NULL)
+        return;
+    jl_mmtk_ephemeron_type = jl_new_datatype(jl_symbol("Ephemeron"), jl_core_module, jl_any_type,
+                                             jl_emptysvec, jl_perm_symsvec(2, "key", "value"),
+                                             jl_svec(2, jl_any_type, jl_any_type), jl_emptysvec,
+                                             0, 1, 2);
+    jl_set_const(jl_core_module, jl_symbol("Ephemeron"), (jl_value_t*)jl_mmtk_ephemeron_type);
+    mmtk_set_ephemeron_type(jl_mmtk_ephemeron_type);
+}
+
+JL_DLLEXPORT jl_value_t *jl_mmtk_new_ephemeron(jl_value_t *key, jl_value_t *value)
+{
+    assert(jl_mmtk_ephemeron_type != NULL);
+    jl_task_t *ct = jl_current_task;
+    jl_value_t **e = (jl_value_t**)jl_gc_alloc(ct->ptls, 2 * sizeof(void*), jl_mmtk_ephemeron_type);
+    e[0] = key;
+    e[1] = value;
+    return (jl_value_t*)e;
+}
+
+// `mutable struct SoftRef; value; end`. The binding keeps the value of a soft reference alive unless
+// the heap was nearly full after the last GC (see `prepare_soft_refs` in weak_refs.rs), in which case
+// the value is set to `nothing` if nothing else refers to it. This is useful for memory-sensitive
+// caches.
+static jl_datatype_t *jl_mmtk_soft_ref_type = NULL;
+
+// Create the SoftRef type, and bind it as `Core.SoftRef`, which also keeps it alive. This should be
+// called once Core is set up, before any soft reference is created.
+JL_DLLEXPORT void jl_mmtk_init_soft_ref_type(void)
+{
+    if (jl_mmtk_soft_ref_type != NULL)
+        return;
+    jl_mmtk_soft_ref_type = jl_new_datatype(jl_symbol("SoftRef"), jl_core_module, jl_any_type,
+                                            jl_emptysvec, jl_perm_symsvec(1, "value"),
+                                            jl_svec1(jl_any_type), jl_emptysvec, 0, 1, 1);
+    jl_set_const(jl_core_module, jl_symbol("SoftRef"), (jl_value_t*)jl_mmtk_soft_ref_type);
+    mmtk_set_soft_ref_type(jl_mmtk_soft_ref_type);
+}
+
+JL_DLLEXPORT jl_value_t *jl_mmtk_new_soft_ref(jl_value_t *value)
+{
+    assert(jl_mmtk_soft_ref_type != NULL);
+    jl_task_t *ct = jl_current_task;
+    jl_value_t **r = (jl_value_t**)jl_gc_alloc(ct->ptls, sizeof(void*), jl_mmtk_soft_ref_type);
+    r[0] = value;
+    return (jl_value_t*)r;
+}
+
 static void add_node_to_roots_buffer(RootsWorkClosure* closure, RootsWorkBuffer* buf, size_t* buf_len, void* root) {
     if (root == NULL)
         return;
@@ -427,6 +592,8 @@ void scan_vm_specific_roots(RootsWorkClosure* closure)
     add_node_to_roots_buffer(closure, &buf, &len, jl_emptytuple_type);
     add_node_to_roots_buffer(closure, &buf, &len, cmpswap_names);
     add_node_to_roots_buffer(closure, &buf, &len, jl_global_roots_table);
+    add_node_to_roots_buffer(closure, &buf, &len, jl_mmtk_ephemeron_type);
+    add_node_to_roots_buffer(closure, &buf, &len, jl_mmtk_soft_ref_type);
 
     // Push the result of the work.
     (closure->report_nodes_func)(buf.ptr, len, buf.cap, closure->data, false);
@@ -519,6 +686,7 @@ uintptr_t get_abi_structs_checksum_c(void) {
     assert_size(struct mmtk__jl_gcframe_t, struct _jl_gcframe_t);
     assert_size(mmtk_jl_task_t, jl_task_t);
     assert_size(mmtk_jl_weakref_t, jl_weakref_t);
+    assert_size(mmtk_mallocarray_t, mallocarray_t);
 
     return print_sizeof(MMTkMutatorContext)
         ^ print_sizeof(struct mmtk__jl_taggedvalue_bits)
@@ -545,16 +713,16 @@ uintptr_t get_abi_structs_checksum_c(void) {
         ^ print_sizeof(mmtk_jl_weakref_t)
         ^ print_sizeof(mmtk_jl_tls_states_t)
         ^ print_sizeof(mmtk_jl_thread_heap_t)
-        ^ print_sizeof(mmtk_jl_thread_gc_num_t);
+        ^ print_sizeof(mmtk_jl_thread_gc_num_t)
+        ^ print_sizeof(mmtk_mallocarray_t);
 }
 
 Julia_Upcalls mmtk_upcalls = (Julia_Upcalls) {
     .scan_julia_exc_obj = scan_julia_exc_obj,
     .get_stackbase = get_stackbase,
-    // .run_finalizer_function = run_finalizer_function,
     .mmtk_jl_run_finalizers = mmtk_jl_run_finalizers,
     .jl_throw_out_of_memory_error = jl_throw_out_of_memory_error,
-    .sweep_malloced_array = mmtk_sweep_malloced_arrays,
+    .sweep_sysimg = mmtk_sweep_sysimg,
     .wait_in_a_safepoint = mmtk_wait_in_a_safepoint,
     .exit_from_safepoint = mmtk_exit_from_safepoint,
     .jl_hrtime = jl_hrtime,
@@ -567,4 +735,11 @@ Julia_Upcalls mmtk_upcalls = (Julia_Upcalls) {
     .get_jl_gc_have_pending_finalizers = get_jl_gc_have_pending_finalizers,
     .scan_vm_specific_roots = scan_vm_specific_roots,
     .prepare_to_collect = jl_gc_prepare_to_collect,
+    .lock_finalizers = mmtk_lock_finalizers,
+    .unlock_finalizers = mmtk_unlock_finalizers,
+    .get_orphan_finalizers_list = get_orphan_finalizers_list,
+    .run_finalizer_function = run_finalizer_function,
+    .run_finalizer_thread = mmtk_run_finalizer_thread,
+    .get_current_ptls = get_current_ptls,
+    .free_array = mmtk_free_array,
 };
@@ -309,6 +309,11 @@ typedef struct {
     _Atomic(uint64_t) freecall;
 } mmtk_jl_thread_gc_num_t;
 
+typedef struct mmtk__mallocarray_t {
+    mmtk_jl_array_t *a;
+    struct mmtk__mallocarray_t *next;
+} mmtk_mallocarray_t;
+
 typedef struct {
     // variable for tracking weak references
     mmtk_arraylist_t weak_refs;
@@ -317,8 +322,8 @@ typedef struct {
     mmtk_arraylist_t live_tasks;
 
     // variables for tracking malloc'd arrays
-    struct _mallocarray_t *mallocarrays;
-    struct _mallocarray_t *mafreelist;
+    struct mmtk__mallocarray_t *mallocarrays;
+    struct mmtk__mallocarray_t *mafreelist;
 
     // variables for tracking big objects
     struct _bigval_t *big_objects;
@@ -32,6 +32,14 @@ extern MMTk_Mutator mmtk_bind_mutator(void *tls, int tid);
 extern void mmtk_post_bind_mutator(MMTk_Mutator mutator, MMTk_Mutator original_mutator);
 extern void mmtk_destroy_mutator(MMTk_Mutator mutator);
 
+// A copy of the threads of the mutators that are bound. Free it with mmtk_free_mutator_snapshot.
+typedef struct {
+    size_t count;
+    void** tls;
+} MMTk_MutatorSnapshot;
+extern MMTk_MutatorSnapshot mmtk_mutator_snapshot(void);
+extern void mmtk_free_mutator_snapshot(MMTk_MutatorSnapshot snapshot);
+
 extern void* mmtk_alloc(MMTk_Mutator mutator, size_t size,
     size_t align, size_t offset, int allocator);
 
@@ -58,6 +66,10 @@ extern void mmtk_object_reference_write_slow(MMTk_Mutator mutator, const void* s
 extern const void* MMTK_SIDE_LOG_BIT_BASE_ADDRESS;
 
 extern uintptr_t JULIA_MALLOC_BYTES;
+extern void* mmtk_counted_malloc(void *tls, size_t size);
+extern void* mmtk_counted_calloc(void *tls, size_t nmemb, size_t size);
+extern void* mmtk_counted_realloc(void *tls, void* ptr, size_t old_size, size_t new_size);
+extern void mmtk_counted_free(void* ptr, size_t size);
 
 /**
  * Julia-specific
@@ -71,7 +83,7 @@ typedef struct {
     void* (* get_stackbase) (int16_t tid);
     void (* mmtk_jl_run_finalizers) (void* tls);
     void (* jl_throw_out_of_memory_error) (void);
-    void (* sweep_malloced_array) (void);
+    void (* sweep_sysimg) (void);
     void (* wait_in_a_safepoint) (void);
     void (* exit_from_safepoint) (int8_t old_state);
     uint64_t (* jl_hrtime) (void);
@@ -83,26 +95,52 @@ typedef struct {
     void (*arraylist_grow)(void* a, size_t n);
     int* (*get_jl_gc_have_pending_finalizers)(void);
     void (*scan_vm_specific_roots)(RootsWorkClosure* closure);
-    void (*prepare_to_collect)(void);
+    bool (*prepare_to_collect)(int reason);
+    void (*lock_finalizers)(void);
+    void (*unlock_finalizers)(void);
+    void* (*get_orphan_finalizers_list)(void);
+    void (*run_finalizer_function)(void* obj, void* function, bool is_ptr);
+    void (*run_finalizer_thread)(void);
+    void* (*get_current_ptls)(void);
+    void (*free_array)(void* a);
 } Julia_Upcalls;
 
 /**
  * Misc
  */
 extern void mmtk_gc_init(uintptr_t min_heap_size, uintptr_t max_heap_size, uintptr_t n_gcthreads, Julia_Upcalls *calls, uintptr_t header_size, uintptr_t tag);
+extern void mmtk_gc_shutdown(void);
 extern bool mmtk_will_never_move(void* object);
 extern bool mmtk_process(char* name, char* value);
+
+// See OptionStatus in options.rs
+typedef enum {
+    MMTK_OPTION_OK = 0,
+    MMTK_OPTION_INVALID_UTF8 = 1,
+    MMTK_OPTION_UNKNOWN_KEY = 2,
+    MMTK_OPTION_INVALID_VALUE = 3,
+} MMTk_OptionStatus;
+extern MMTk_OptionStatus mmtk_process_option(char* name, char* value);
 extern void mmtk_scan_region(void);
 extern void mmtk_handle_user_collection_request(void *tls, uint8_t collection);
 extern void mmtk_initialize_collection(void* tls);
 extern void mmtk_enable_collection(void);
 extern void mmtk_disable_collection(void);
+extern size_t mmtk_deferred_alloc_bytes(void);
 extern void mmtk_start_control_collector(void *tls);
 extern void mmtk_start_worker(void *tls, void* worker, void* mmtk);
 extern void mmtk_process_julia_obj(void* addr);
+extern bool mmtk_los_array_buffers_enabled(void);
+extern bool mmtk_native_finalizers_enabled(void);
 extern void mmtk_register_finalizer(void* obj, void* function, bool is_ptr);
 extern void mmtk_run_finalizers_for_obj(void* obj);
 extern void mmtk_run_finalizers(bool at_exit);
+extern bool mmtk_start_finalizer_thread(void);
+extern bool mmtk_finalizer_thread_running(void);
+extern bool mmtk_finalizer_thread_wait_for_work(void);
+extern void mmtk_finalizer_thread_done(void);
+extern bool mmtk_wait_for_finalizers(void);
+extern size_t mmtk_finalizer_queue_depth(void);
 extern void mmtk_gc_poll(void *tls);
 extern void mmtk_julia_copy_stack_check(int copy_stack);
 
@@ -112,12 +150,63 @@ extern void mmtk_julia_copy_stack_check(int copy_stack);
 extern size_t mmtk_free_bytes(void);
 extern size_t mmtk_total_bytes(void);
 extern size_t mmtk_used_bytes(void);
+extern void mmtk_get_heap_limits(size_t* min, size_t* max);
+extern void mmtk_get_time_to_safepoint(uint64_t* last, uint64_t* max, uint64_t* total);
+
+// Why a GC is triggered. See GCReason in gc_trigger.rs.
+typedef enum {
+    MMTK_GC_REASON_HEAP_FULL = 0,
+    MMTK_GC_REASON_USER_AUTO = 1,
+    MMTK_GC_REASON_USER_FULL = 2,
+    MMTK_GC_REASON_USER_INCREMENTAL = 3,
+    MMTK_GC_REASON_MALLOC = 4,
+    MMTK_GC_REASON_EMERGENCY = 5,
+} MMTk_GCReason;
+
+// Statistics of the last GC. See GCStats in gc_stats.rs. Times are in nanoseconds.
+typedef struct {
+    uint64_t gc_count;
+    bool is_nursery;
+    MMTk_GCReason reason;
+    uint64_t used_bytes_before;
+    uint64_t used_bytes_after;
+    uint64_t vm_live_bytes;
+    uint64_t mutators;
+    uint64_t finalizers_queued;
+    uint64_t total_time;
+    uint64_t time_to_safepoint;
+    uint64_t stop_the_world;
+    uint64_t mutator_roots;
+    uint64_t vm_roots;
+    uint64_t closure;
+    uint64_t finalizers;
+    uint64_t sweep_malloced_arrays;
+    uint64_t release;
+} MMTk_GCStats;
+extern void mmtk_last_gc_stats(MMTk_GCStats* stats);
+
+// GC callbacks. They have the same signature as jl_gc_cb_pre_gc_t and jl_gc_cb_post_gc_t.
+typedef enum {
+    MMTK_PRE_GC_CALLBACK = 0,
+    MMTK_POST_GC_CALLBACK = 1,
+} MMTk_GCCallbackKind;
+typedef void (*MMTk_GCCallback)(int full);
+extern bool mmtk_register_gc_callback(uint8_t kind, MMTk_GCCallback callback);
+extern bool mmtk_unregister_gc_callback(uint8_t kind, MMTk_GCCallback callback);
+
+// Called when the heap is exhausted, before throwing an OutOfMemoryError. Set NULL to unset.
+typedef void (*MMTk_OOMCallback)(void);
+extern void mmtk_set_oom_callback(MMTk_OOMCallback callback);
+extern bool mmtk_set_heap_limits(void *tls, size_t min, size_t max);
 extern void* mmtk_starting_heap_address(void);
 extern void* mmtk_last_heap_address(void);
 
 /**
  * Reference Processing
  */
+extern void mmtk_set_ephemeron_type(void* type);
+extern void mmtk_set_soft_ref_type(void* type);
+// Deprecated: weak references are discovered when they are scanned. This does nothing.
 extern void mmtk_add_weak_candidate(void* ref);
 extern void mmtk_add_soft_candidate(void* ref);
 extern void mmtk_add_phantom_candidate(void* ref);
@@ -9,16 +9,74 @@ use mmtk::{plan::ObjectQueue, scheduler::GCWorker, util::ObjectReference};
 use std::collections::HashMap;
 use std::sync::RwLockReadGuard;
 
+/// The mutators that are bound. We create a boxed mutator with MMTk core, and Julia copies its
+/// content to jl_tls_state_t (a shallow copy). We keep the original boxed mutator until the thread
+/// is destroyed, otherwise there would be dangling pointers in the copy. The mutators are also
+/// indexed by their thread, so we can find the mutator of a thread in constant time.
+#[derive(Default)]
+pub struct MutatorRegistry {
+    // The mutator in jl_tls_state_t -> the original boxed mutator
+    boxed: HashMap<Address, Address>,
+    // The mutator thread -> the mutator in jl_tls_state_t
+    by_tls: HashMap<Address, Address>,
+}
+
+impl MutatorRegistry {
+    /// Register a mutator after it is copied to jl_tls_state_t.
+    pub fn insert(&mut self, mutator: *mut Mutator<JuliaVM>, boxed: *mut Mutator<JuliaVM>) {
+        let tls = unsafe { &*mutator }.mutator_tls;
+        let mutator = Address::from_mut_ptr(mutator);
+        self.boxed.insert(mutator, Address::from_mut_ptr(boxed));
+        self.by_tls.insert(tls.0 .0.to_address(), mutator);
+    }
+
+    /// Unregister a mutator, and return its original boxed mutator.
+    pub fn remove(&mut self, mutator: *mut Mutator<JuliaVM>) -> Option<*mut Mutator<JuliaVM>> {
+        let tls = unsafe { &*mutator }.mutator_tls;
+        let boxed = self.boxed.remove(&Address::from_mut_ptr(mutator))?;
+        self.by_tls.remove(&tls.0 .0.to_address());
+        Some(boxed.to_mut_ptr())
+    }
+
+    /// The mutator of the thread, if the thread is a mutator.
+    pub fn get(&self, tls: VMThread) -> Option<*mut Mutator<JuliaVM>> {
+        self.by_tls
+            .get(&tls.0.to_address())
+            .map(|mutator| mutator.to_mut_ptr())
+    }
+
+    /// Whether the mutator is registered, and not destroyed yet.
+    pub fn is_bound(&self, mutator: *mut Mutator<JuliaVM>) -> bool {
+        self.boxed.contains_key(&Address::from_mut_ptr(mutator))
+    }
+
+    pub fn contains(&self, tls: VMThread) -> bool {
+        self.by_tls.contains_key(&tls.0.to_address())
+    }
+
+    pub fn len(&self) -> usize {
+        self.boxed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boxed.is_empty()
+    }
+
+    fn mutators(&self) -> impl Iterator<Item = Address> + '_ {
+        self.boxed.keys().copied()
+    }
+}
+
 pub struct JuliaMutatorIterator<'a> {
     // We do not use this field, but this lock guard makes sure that no concurrent access to MUTATORS.
-    _guard: RwLockReadGuard<'a, HashMap<Address, Address>>,
+    _guard: RwLockReadGuard<'a, MutatorRegistry>,
     vec: Vec<Address>,
     cursor: usize,
 }
 
 impl<'a> JuliaMutatorIterator<'a> {
-    fn new(guard: RwLockReadGuard<'a, HashMap<Address, Address>>) -> Self {
-        let vec = guard.keys().map(|addr| *addr).collect();
+    fn new(guard: RwLockReadGuard<'a, MutatorRegistry>) -> Self {
+        let vec = guard.mutators().collect();
         Self {
             _guard: guard,
             vec,
@@ -44,16 +102,18 @@ pub struct VMActivePlan {}
 
 impl ActivePlan<JuliaVM> for VMActivePlan {
     fn number_of_mutators() -> usize {
-        Self::mutators().count()
+        MUTATORS.read().unwrap().len()
     }
 
     fn is_mutator(tls: VMThread) -> bool {
-        // FIXME have a tls field to check whether it is a mutator tls
-        MUTATORS.read().unwrap().keys().find(|mutator_addr| unsafe { &*mutator_addr.to_mut_ptr::<Mutator<JuliaVM>>() }.mutator_tls.0 == tls).is_some()
+        MUTATORS.read().unwrap().contains(tls)
     }
 
-    fn mutator(_tls: VMMutatorThread) -> &'static mut Mutator<JuliaVM> {
-        unimplemented!()
+    fn mutator(tls: VMMutatorThread) -> &'static mut Mutator<JuliaVM> {
+        match MUTATORS.read().unwrap().get(tls.0) {
+            Some(mutator) => unsafe { &mut *mutator },
+            None => panic!("{:?} is not a mutator", tls),
+        }
     }
 
     fn mutators<'a>() -> Box<dyn Iterator<Item = &'a mut Mutator<JuliaVM>> + 'a> {
@@ -71,24 +131,42 @@ impl ActivePlan<JuliaVM> for VMActivePlan {
     }
 }
 
-// Expose the mutator iterator so they can be used in C.
+/// Copy the mutators that are bound. The lock on `MUTATORS` is only held while copying, so the
+/// caller can go through the mutators while other threads bind mutators. During a GC, a thread that
+/// exits waits in `mmtk_destroy_mutator` until the GC is done, so the mutators in the copy stay
+/// valid until the mutators resume. Outside a GC, the copy may have mutators that are destroyed.
+pub fn mutators_snapshot() -> Vec<&'static mut Mutator<JuliaVM>> {
+    MUTATORS
+        .read()
+        .unwrap()
+        .mutators()
+        .map(|addr| unsafe { &mut *(addr.to_mut_ptr::<Mutator<JuliaVM>>()) })
+        .collect()
+}
 
-#[no_mangle]
-pub extern "C" fn new_mutator_iterator() -> *mut JuliaMutatorIterator<'static> {
-    let guard = MUTATORS.read().unwrap();
-    Box::into_raw(Box::new(JuliaMutatorIterator::new(guard)))
+/// A copy of the threads of the mutators that are bound, for C. It is owned by the caller, and
+/// should be freed with `mmtk_free_mutator_snapshot`.
+#[repr(C)]
+pub struct MutatorSnapshot {
+    pub count: usize,
+    pub tls: *mut OpaquePointer,
 }
 
+/// Copy the threads (`jl_ptls_t`) of the mutators that are bound.
 #[no_mangle]
-pub extern "C" fn get_next_mutator_tls(iter: *mut JuliaMutatorIterator<'static>) -> OpaquePointer {
-    match unsafe { iter.as_mut() }.unwrap().next() {
-        Some(m) => m.mutator_tls.0 .0,
-        None => OpaquePointer::from_address(Address::ZERO),
+pub extern "C" fn mmtk_mutator_snapshot() -> MutatorSnapshot {
+    let tls: Box<[OpaquePointer]> = mutators_snapshot()
+        .into_iter()
+        .map(|m| m.mutator_tls.0 .0)
+        .collect();
+    MutatorSnapshot {
+        count: tls.len(),
+        tls: Box::into_raw(tls) as *mut OpaquePointer,
     }
 }
 
 #[no_mangle]
-pub extern "C" fn close_mutator_iterator(iter: *mut JuliaMutatorIterator<'static>) {
-    // The boxed pointer will get dropped
-    let _to_drop = unsafe { Box::from_raw(iter) };
+pub extern "C" fn mmtk_free_mutator_snapshot(snapshot: MutatorSnapshot) {
+    let slice = std::ptr::slice_from_raw_parts_mut(snapshot.tls, snapshot.count);
+    let _to_drop = unsafe { Box::from_raw(slice) };
 }
@@ -1,13 +1,16 @@
 // All functions here are extern function. There is no point for marking them as unsafe.
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
+use crate::gc_trigger::GCReason;
+use crate::options::{OptionError, OptionStatus};
+use crate::reference_glue::JuliaFinalizableObject;
 use crate::JuliaVM;
 use crate::Julia_Upcalls;
 use crate::BLOCK_FOR_GC;
 use crate::JULIA_HEADER_SIZE;
 use crate::SINGLETON;
 use crate::UPCALLS;
-use crate::{BUILDER, DISABLED_GC, MUTATORS, USER_TRIGGERED_GC};
+use crate::{BUILDER, MUTATORS, STW_COND};
 
 use libc::c_char;
 use log::*;
@@ -15,11 +18,11 @@ use mmtk::memory_manager;
 use mmtk::scheduler::GCController;
 use mmtk::scheduler::GCWorker;
 use mmtk::util::opaque_pointer::*;
+use mmtk::util::options::GCTriggerSelector;
 use mmtk::util::{Address, ObjectReference, OpaquePointer};
 use mmtk::AllocationSemantics;
 use mmtk::Mutator;
 use std::ffi::CStr;
-use std::sync::atomic::AtomicIsize;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 #[no_mangle]
@@ -46,45 +49,21 @@ pub extern "C" fn mmtk_gc_init(
     {
         let mut builder = BUILDER.lock().unwrap();
 
-        // Set plan
-        use mmtk::util::options::PlanSelector;
-        let force_plan = if cfg!(feature = "nogc") {
-            Some(PlanSelector::NoGC)
-        } else if cfg!(feature = "marksweep") {
-            Some(PlanSelector::MarkSweep)
-        } else if cfg!(feature = "immix") {
-            Some(PlanSelector::Immix)
-        } else if cfg!(feature = "stickyimmix") {
-            Some(PlanSelector::StickyImmix)
-        } else {
-            None
-        };
-        if let Some(plan) = force_plan {
-            builder.options.plan.set(plan);
-        }
-
         // Set heap size
-        let success;
-        if min_heap_size != 0 {
+        let trigger = if min_heap_size != 0 {
             info!(
                 "Setting mmtk heap size to a variable size with min-max of {}-{} (in bytes)",
                 min_heap_size, max_heap_size
             );
-            success = builder.options.gc_trigger.set(
-                mmtk::util::options::GCTriggerSelector::DynamicHeapSize(
-                    min_heap_size,
-                    max_heap_size,
-                ),
-            );
+            GCTriggerSelector::DynamicHeapSize(min_heap_size, max_heap_size)
         } else {
             info!(
                 "Setting mmtk heap size to a fixed max of {} (in bytes)",
                 max_heap_size
             );
-            success = builder.options.gc_trigger.set(
-                mmtk::util::options::GCTriggerSelector::FixedHeapSize(max_heap_size),
-            );
-        }
+            GCTriggerSelector::FixedHeapSize(max_heap_size)
+        };
+        let success = builder.options.gc_trigger.set(trigger);
         assert!(
             success,
             "Failed to set heap size to {}-{}",
@@ -100,8 +79,62 @@ pub extern "C" fn mmtk_gc_init(
             let success = builder.options.threads.set(n_gcthreads);
             assert!(success, "Failed to set GC threads to {}", n_gcthreads);
         }
+
+        // Set options from the config file and MMTK_OPTIONS. They override the arguments above.
+        // Report every invalid option, and keep going with the valid ones.
+        for error in crate::options::process_env(&mut builder) {
+            eprintln!("[MMTk] Ignoring option: {}", error);
+        }
+
+        // If the heap size is not set explicitly, size the heap from the container memory limit.
+        // The default heap size is computed from the memory of the machine, which is too large in
+        // a container and gets us killed.
+        if *builder.options.gc_trigger == trigger && !crate::cgroup::has_explicit_heap_size() {
+            if let Some(limit) = crate::cgroup::memory_limit() {
+                let headroom = crate::options::JULIA_OPTIONS
+                    .read()
+                    .unwrap()
+                    .malloc_headroom;
+                let (min, max) =
+                    crate::cgroup::heap_size_from_limit(limit, min_heap_size, headroom);
+                info!(
+                    "Setting mmtk heap size to min-max of {}-{} (in bytes) from the container limit of {} bytes",
+                    min, max, limit
+                );
+                let success = builder.options.gc_trigger.set(if min != 0 {
+                    GCTriggerSelector::DynamicHeapSize(min, max)
+                } else {
+                    GCTriggerSelector::FixedHeapSize(max)
+                });
+                assert!(success, "Failed to set heap size to {}-{}", min, max);
+            }
+        }
+
+        // Set up our own GC triggers: the malloc trigger, and the delegated trigger with the heap
+        // limits that can be changed at runtime with mmtk_set_heap_limits, if it is asked for.
+        crate::gc_trigger::init(&mut builder.options, trigger);
+
+        // Set plan. This is done last, as the plan has to match the allocation fastpath in Julia,
+        // and cannot be changed by the options above.
+        use mmtk::util::options::PlanSelector;
+        let force_plan = if cfg!(feature = "nogc") {
+            Some(PlanSelector::NoGC)
+        } else if cfg!(feature = "marksweep") {
+            Some(PlanSelector::MarkSweep)
+        } else if cfg!(feature = "immix") {
+            Some(PlanSelector::Immix)
+        } else if cfg!(feature = "stickyimmix") {
+            Some(PlanSelector::StickyImmix)
+        } else {
+            None
+        };
+        if let Some(plan) = force_plan {
+            builder.options.plan.set(plan);
+        }
     }
 
+    crate::gc_log::init();
+
     // Make sure that we haven't initialized MMTk (by accident) yet
     assert!(!crate::MMTK_INITIALIZED.load(Ordering::SeqCst));
     // Make sure we initialize MMTk here
@@ -157,26 +190,48 @@ pub extern "C" fn mmtk_post_bind_mutator(
     original_box_mutator: *mut Mutator<JuliaVM>,
 ) {
     // We have to store the original boxed mutator. Otherwise, we may have dangling pointers in mutator.
-    MUTATORS.write().unwrap().insert(
-        Address::from_mut_ptr(mutator),
-        Address::from_mut_ptr(original_box_mutator),
-    );
+    MUTATORS
+        .write()
+        .unwrap()
+        .insert(mutator, original_box_mutator);
 }
 
+/// Destroy the mutator of a thread that exits. The thread must be in a GC safe state, as we may wait
+/// for a GC here.
 #[no_mangle]
 pub extern "C" fn mmtk_destroy_mutator(mutator: *mut Mutator<JuliaVM>) {
-    // destroy the mutator with MMTk.
-    memory_manager::destroy_mutator(unsafe { &mut *mutator });
+    // mmtk_gc_shutdown destroys the mutators that are still bound, and their threads may exit later.
+    if !MUTATORS.read().unwrap().is_bound(mutator) {
+        warn!("Destroying mutator {:?} that is not registered", mutator);
+        return;
+    }
 
-    let mut mutators = MUTATORS.write().unwrap();
-    let key = Address::from_mut_ptr(mutator);
+    // Keep the finalizers that the thread registered, so they still run after the thread exits.
+    crate::julia_finalizer::orphan_thread_finalizers(unsafe { &*mutator });
+
+    // A GC goes through a copy of the mutators (see `mutators_snapshot`), so we wait for the GC in
+    // progress before destroying the mutator. Holding the lock keeps the next GC from stopping the
+    // world until the mutator is removed from the registry.
+    let &(ref lock, ref cvar) = &*STW_COND.clone();
+    let mut count = lock.lock().unwrap();
+    while AtomicBool::load(&BLOCK_FOR_GC, Ordering::SeqCst) {
+        count = cvar.wait(count).unwrap();
+    }
 
-    // Clear the original boxed mutator
-    let orig_mutator = mutators.get(&key).unwrap();
-    let _ = unsafe { Box::from_raw(orig_mutator.to_mut_ptr::<Mutator<JuliaVM>>()) };
+    crate::julia_malloced_arrays::forget_mutator(unsafe { &*mutator });
+
+    // destroy the mutator with MMTk.
+    memory_manager::destroy_mutator(unsafe { &mut *mutator });
 
-    // Remove from our hashmap
-    mutators.remove(&key);
+    // Remove from our registry, and clear the original boxed mutator. Do not panic across FFI if the
+    // mutator is not registered.
+    match MUTATORS.write().unwrap().remove(mutator) {
+        Some(orig_mutator) => {
+            let _ = unsafe { Box::from_raw(orig_mutator) };
+        }
+        None => warn!("Destroying mutator {:?} that is not registered", mutator),
+    }
+    drop(count);
 }
 
 #[no_mangle]
@@ -195,7 +250,10 @@ pub extern "C" fn mmtk_alloc(
         "Alloc size {} is not aligned to min alignment",
         size
     );
-    memory_manager::alloc::<JuliaVM>(unsafe { &mut *mutator }, size, align, offset, semantics)
+    let mutator = unsafe { &mut *mutator };
+    crate::oom::alloc_with_retry(|| {
+        memory_manager::alloc::<JuliaVM>(mutator, size, align, offset, semantics)
+    })
 }
 
 #[no_mangle]
@@ -205,13 +263,10 @@ pub extern "C" fn mmtk_alloc_large(
     align: usize,
     offset: usize,
 ) -> Address {
-    memory_manager::alloc::<JuliaVM>(
-        unsafe { &mut *mutator },
-        size,
-        align,
-        offset,
-        AllocationSemantics::Los,
-    )
+    let mutator = unsafe { &mut *mutator };
+    crate::oom::alloc_with_retry(|| {
+        memory_manager::alloc::<JuliaVM>(mutator, size, align, offset, AllocationSemantics::Los)
+    })
 }
 
 #[no_mangle]
@@ -240,30 +295,33 @@ pub extern "C" fn mmtk_initialize_collection(tls: VMThread) {
     memory_manager::initialize_collection(&SINGLETON, tls);
 }
 
+/// Enable collection for a previous `mmtk_disable_collection`. Collection is only enabled when every
+/// disable is matched by an enable, like `jl_gc_disable_counter`. Then the GC that was deferred while
+/// collection was disabled runs on this thread.
 #[no_mangle]
 pub extern "C" fn mmtk_enable_collection() {
-    if AtomicBool::load(&DISABLED_GC, Ordering::SeqCst) {
-        memory_manager::enable_collection(&SINGLETON);
-        AtomicBool::store(&DISABLED_GC, false, Ordering::SeqCst);
-    }
+    let tls = unsafe { ((*UPCALLS).get_current_ptls)() };
+    crate::gc_trigger::enable_collection(VMMutatorThread(VMThread(tls)));
 }
 
+/// Disable collection. This can be nested. If a GC is running, wait until it is finished, like
+/// `jl_gc_enable` in Julia.
 #[no_mangle]
 pub extern "C" fn mmtk_disable_collection() {
-    if AtomicBool::load(&DISABLED_GC, Ordering::SeqCst) == false {
-        AtomicBool::store(&DISABLED_GC, true, Ordering::SeqCst);
-        memory_manager::disable_collection(&SINGLETON);
-    }
+    crate::gc_trigger::disable_collection();
 
-    // if user has triggered GC, wait until GC is finished
-    while AtomicIsize::load(&USER_TRIGGERED_GC, Ordering::SeqCst) != 0
-        || AtomicBool::load(&BLOCK_FOR_GC, Ordering::SeqCst)
-    {
+    while AtomicBool::load(&BLOCK_FOR_GC, Ordering::SeqCst) {
         info!("Waiting for a triggered gc to finish...");
         unsafe { ((*UPCALLS).wait_in_a_safepoint)() };
     }
 }
 
+/// The bytes allocated while collection was disabled, in total.
+#[no_mangle]
+pub extern "C" fn mmtk_deferred_alloc_bytes() -> usize {
+    crate::gc_trigger::deferred_alloc_bytes()
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_used_bytes() -> usize {
     memory_manager::used_bytes(&SINGLETON)
@@ -279,6 +337,31 @@ pub extern "C" fn mmtk_total_bytes() -> usize {
     memory_manager::total_bytes(&SINGLETON)
 }
 
+/// Get the current heap limits in bytes. A min of 0 means the heap has a fixed size of max.
+#[no_mangle]
+pub extern "C" fn mmtk_get_heap_limits(min: *mut usize, max: *mut usize) {
+    let (min_heap_size, max_heap_size) = crate::gc_trigger::get_heap_limits();
+    unsafe {
+        *min = min_heap_size;
+        *max = max_heap_size;
+    }
+}
+
+/// Change the heap limits in bytes. A min of 0 means the heap has a fixed size of max. Returns false
+/// if the limits are invalid, or if MMTk does not use the delegated trigger (`gc_trigger=Delegated`).
+/// If the heap is already over the new heap size, a collection is triggered right away, unless
+/// collection is disabled.
+#[no_mangle]
+pub extern "C" fn mmtk_set_heap_limits(tls: VMMutatorThread, min: usize, max: usize) -> bool {
+    if !crate::gc_trigger::set_heap_limits(min, max) {
+        return false;
+    }
+    if SINGLETON.get_plan().is_initialized() && !crate::gc_trigger::is_collection_disabled() {
+        memory_manager::gc_poll(&SINGLETON, tls);
+    }
+    true
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_is_live_object(object: ObjectReference) -> bool {
     object.is_live()
@@ -291,27 +374,30 @@ pub extern "C" fn mmtk_is_mapped_address(address: Address) -> bool {
 
 #[no_mangle]
 pub extern "C" fn mmtk_handle_user_collection_request(tls: VMMutatorThread, collection: u8) {
-    AtomicIsize::fetch_add(&USER_TRIGGERED_GC, 1, Ordering::SeqCst);
-    if AtomicBool::load(&DISABLED_GC, Ordering::SeqCst) {
-        AtomicIsize::fetch_add(&USER_TRIGGERED_GC, -1, Ordering::SeqCst);
-        return;
-    }
     // See jl_gc_collection_t
     match collection {
         // auto
-        0 => memory_manager::handle_user_collection_request::<JuliaVM>(&SINGLETON, tls),
+        0 => {
+            crate::gc_trigger::set_pending_gc_reason(GCReason::UserAuto);
+            if crate::gc_trigger::defer_gc() {
+                return;
+            }
+            memory_manager::handle_user_collection_request::<JuliaVM>(&SINGLETON, tls)
+        }
         // full
-        1 => SINGLETON.handle_user_collection_request(tls, true, true),
+        1 => crate::gc_trigger::request_gc(tls, GCReason::UserFull, true),
         // incremental
-        2 => SINGLETON.handle_user_collection_request(tls, true, false),
+        2 => crate::gc_trigger::request_gc(tls, GCReason::UserIncremental, false),
         _ => unreachable!(),
     }
 }
 
+/// Deprecated. A `WeakRef` is discovered when it is scanned (see `weak_refs::discover_weak_ref`),
+/// and does not need to be registered. This is kept for callers that still register weak references
+/// when they create them, and does nothing.
 #[no_mangle]
-pub extern "C" fn mmtk_add_weak_candidate(reff: ObjectReference) {
-    memory_manager::add_weak_candidate(&SINGLETON, reff)
-}
+#[deprecated(note = "weak references are discovered when they are scanned")]
+pub extern "C" fn mmtk_add_weak_candidate(_reff: ObjectReference) {}
 
 #[no_mangle]
 pub extern "C" fn mmtk_add_soft_candidate(reff: ObjectReference) {
@@ -335,14 +421,30 @@ pub extern "C" fn mmtk_harness_end(_tls: OpaquePointer) {
 
 #[no_mangle]
 pub extern "C" fn mmtk_process(name: *const c_char, value: *const c_char) -> bool {
+    mmtk_process_option(name, value) == OptionStatus::Ok
+}
+
+/// Set an option, and return why it failed if it did. An error message is printed for failures.
+#[no_mangle]
+pub extern "C" fn mmtk_process_option(name: *const c_char, value: *const c_char) -> OptionStatus {
     let name_str: &CStr = unsafe { CStr::from_ptr(name) };
     let value_str: &CStr = unsafe { CStr::from_ptr(value) };
-    let mut builder = BUILDER.lock().unwrap();
-    memory_manager::process(
-        &mut builder,
-        name_str.to_str().unwrap(),
-        value_str.to_str().unwrap(),
-    )
+    let result = match (name_str.to_str(), value_str.to_str()) {
+        (Ok(name), Ok(value)) => {
+            let mut builder = BUILDER.lock().unwrap();
+            crate::options::process(&mut builder, name, value)
+        }
+        _ => Err(OptionError::InvalidUtf8 {
+            key: name_str.to_string_lossy().into_owned(),
+        }),
+    };
+    match result {
+        Ok(()) => OptionStatus::Ok,
+        Err(error) => {
+            eprintln!("[MMTk] Failed to set option: {}", error);
+            error.status()
+        }
+    }
 }
 
 #[no_mangle]
@@ -359,6 +461,83 @@ pub extern "C" fn mmtk_last_heap_address() -> Address {
 #[no_mangle]
 pub static JULIA_MALLOC_BYTES: AtomicUsize = AtomicUsize::new(0);
 
+// The counted malloc functions count the bytes into JULIA_MALLOC_BYTES, and may trigger a
+// collection if the malloc'd memory grows too much. Only call them where a GC is allowed.
+// The bytes are buffered per thread, so JULIA_MALLOC_BYTES may lag behind by a small amount.
+
+#[no_mangle]
+pub extern "C" fn mmtk_counted_malloc(tls: VMMutatorThread, size: usize) -> *mut libc::c_void {
+    crate::malloc::counted_malloc(tls, size)
+}
+
+#[no_mangle]
+pub extern "C" fn mmtk_counted_calloc(
+    tls: VMMutatorThread,
+    nmemb: usize,
+    size: usize,
+) -> *mut libc::c_void {
+    crate::malloc::counted_calloc(tls, nmemb, size)
+}
+
+#[no_mangle]
+pub extern "C" fn mmtk_counted_realloc(
+    tls: VMMutatorThread,
+    ptr: *mut libc::c_void,
+    old_size: usize,
+    new_size: usize,
+) -> *mut libc::c_void {
+    crate::malloc::counted_realloc(tls, ptr, old_size, new_size)
+}
+
+#[no_mangle]
+pub extern "C" fn mmtk_counted_free(ptr: *mut libc::c_void, size: usize) {
+    crate::malloc::counted_free(ptr, size)
+}
+
+/// Whether Julia should allocate the data buffers of large arrays with `jl_mmtk_alloc_array_buffer`
+/// (`how == 1`) rather than with malloc (`how == 2`). The buffers are then counted in the heap size,
+/// and there are no malloc'd arrays to sweep.
+#[no_mangle]
+pub extern "C" fn mmtk_los_array_buffers_enabled() -> bool {
+    crate::options::JULIA_OPTIONS
+        .read()
+        .unwrap()
+        .los_array_buffers
+}
+
+/// Whether Julia should register finalizers with `mmtk_register_finalizer` rather than in the
+/// thread local lists.
+#[no_mangle]
+pub extern "C" fn mmtk_native_finalizers_enabled() -> bool {
+    crate::julia_finalizer::native_finalizers_enabled()
+}
+
+/// Register a finalizer with MMTk. `is_ptr` means that `function` is a C function rather than a
+/// Julia function. When the object dies, the finalizer is queued to run after the GC.
+#[no_mangle]
+pub extern "C" fn mmtk_register_finalizer(obj: ObjectReference, function: Address, is_ptr: bool) {
+    memory_manager::add_finalizer(&SINGLETON, JuliaFinalizableObject(obj, function, is_ptr));
+}
+
+/// Run the finalizers registered with MMTk for the object now, e.g. for `finalize(obj)`.
+#[no_mangle]
+pub extern "C" fn mmtk_run_finalizers_for_obj(obj: ObjectReference) {
+    for finalizable in memory_manager::get_finalizers_for(&SINGLETON, obj) {
+        finalizable.run();
+    }
+}
+
+/// Run the finalizers registered with MMTk whose objects are dead. At exit, run all of them.
+#[no_mangle]
+pub extern "C" fn mmtk_run_finalizers(at_exit: bool) {
+    if at_exit {
+        for finalizable in memory_manager::get_all_finalizers(&SINGLETON) {
+            finalizable.run();
+        }
+    }
+    crate::julia_finalizer::run_ready_finalizers();
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_gc_poll(tls: VMMutatorThread) {
     memory_manager::gc_poll(&SINGLETON, tls);
@@ -0,0 +1,176 @@
+use log::*;
+use std::path::{Path, PathBuf};
+
+// The environment variables that Julia reads to set the heap size. If any of them is set, the user
+// has chosen a heap size, and we do not size the heap from the container limit.
+const HEAP_SIZE_ENV_VARS: [&str; 4] = [
+    "MMTK_MIN_HSIZE",
+    "MMTK_MAX_HSIZE",
+    "MMTK_MIN_HSIZE_G",
+    "MMTK_MAX_HSIZE_G",
+];
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Returns true if the user has set the heap size with the environment variables.
+pub fn has_explicit_heap_size() -> bool {
+    HEAP_SIZE_ENV_VARS
+        .iter()
+        .any(|var| std::env::var_os(var).is_some())
+}
+
+/// Find the memory limit (in bytes) of the cgroup that we are running in. Both cgroup v2
+/// (`memory.max`) and cgroup v1 (`memory.limit_in_bytes`) are supported. Returns `None` if there is
+/// no limit, or if the limit is not lower than the physical memory.
+pub fn memory_limit() -> Option<usize> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let limit = memory_limit_v2(&cgroups).or_else(|| memory_limit_v1(&cgroups))?;
+
+    // cgroup v1 reports a very large number for no limit.
+    let physical_memory = crate::gc_trigger::physical_memory();
+    if physical_memory != 0 && limit >= physical_memory {
+        return None;
+    }
+    info!("Found cgroup memory limit of {} bytes", limit);
+    Some(limit)
+}
+
+fn memory_limit_v2(cgroups: &str) -> Option<usize> {
+    read_limit(
+        Path::new(CGROUP_ROOT),
+        cgroup_path_v2(cgroups),
+        "memory.max",
+    )
+}
+
+fn memory_limit_v1(cgroups: &str) -> Option<usize> {
+    read_limit(
+        &Path::new(CGROUP_ROOT).join("memory"),
+        cgroup_path_v1(cgroups),
+        "memory.limit_in_bytes",
+    )
+}
+
+/// The path of our cgroup v2 from `/proc/self/cgroup`. The cgroup v2 entry looks like `0::/path`.
+fn cgroup_path_v2(cgroups: &str) -> &str {
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .unwrap_or("/")
+}
+
+/// The path of our cgroup v1 memory controller from `/proc/self/cgroup`. The cgroup v1 entry looks
+/// like `4:memory:/path`, and the memory controller may be listed with other controllers, e.g.
+/// `4:cpu,memory:/path`.
+fn cgroup_path_v1(cgroups: &str) -> &str {
+    cgroups
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+            controllers
+                .split(',')
+                .any(|controller| controller == "memory")
+                .then_some(path)
+        })
+        .next()
+        .unwrap_or("/")
+}
+
+/// Read the limit from the file of our cgroup and of each of its ancestors up to the root of the
+/// mount, and return the lowest one, as the limit of any ancestor applies to us as well. In a
+/// container, the cgroup path that we see may not be mounted, and only the root of the mount is
+/// read.
+fn read_limit(mount: &Path, path: &str, file: &str) -> Option<usize> {
+    let cgroup: PathBuf = mount.join(path.trim_start_matches('/'));
+    cgroup
+        .ancestors()
+        .take_while(|dir| dir.starts_with(mount))
+        .filter_map(|dir| std::fs::read_to_string(dir.join(file)).ok())
+        .filter_map(|content| parse_limit(&content))
+        .min()
+}
+
+/// Parse the content of a limit file. cgroup v2 uses `max` for no limit.
+fn parse_limit(content: &str) -> Option<usize> {
+    content.trim().parse::<usize>().ok()
+}
+
+/// Compute the heap size from the container memory limit, leaving `malloc_headroom` percent of the
+/// limit for the memory that Julia allocates with malloc. Returns the min and the max heap size.
+pub fn heap_size_from_limit(
+    limit: usize,
+    min_heap_size: usize,
+    malloc_headroom: usize,
+) -> (usize, usize) {
+    let max = limit / 100 * (100 - malloc_headroom);
+    (usize::min(min_heap_size, max), max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgroup_path_v2() {
+        assert_eq!(
+            cgroup_path_v2("0::/user.slice/julia\n"),
+            "/user.slice/julia"
+        );
+        assert_eq!(
+            cgroup_path_v2("4:memory:/docker/abc\n0::/docker/abc\n"),
+            "/docker/abc"
+        );
+        assert_eq!(cgroup_path_v2("4:memory:/docker/abc\n"), "/");
+        assert_eq!(cgroup_path_v2(""), "/");
+    }
+
+    #[test]
+    fn test_cgroup_path_v1() {
+        assert_eq!(cgroup_path_v1("4:memory:/docker/abc\n"), "/docker/abc");
+        assert_eq!(
+            cgroup_path_v1("3:cpuacct:/a\n4:cpu,memory:/docker/abc\n"),
+            "/docker/abc"
+        );
+        assert_eq!(cgroup_path_v1("3:memory_pressure:/a\n"), "/");
+        assert_eq!(cgroup_path_v1("0::/docker/abc\n"), "/");
+        assert_eq!(cgroup_path_v1(""), "/");
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit("1073741824\n"), Some(1 << 30));
+        assert_eq!(parse_limit("max\n"), None);
+        assert_eq!(parse_limit(""), None);
+    }
+
+    #[test]
+    fn test_read_limit() {
+        let mount = std::env::temp_dir().join(format!("mmtk-julia-cgroup-{}", std::process::id()));
+        let cgroup = mount.join("a/b/c");
+        std::fs::create_dir_all(&cgroup).unwrap();
+        std::fs::write(mount.join("memory.max"), "4294967296\n").unwrap();
+        std::fs::write(mount.join("a/memory.max"), "1073741824\n").unwrap();
+        std::fs::write(mount.join("a/b/memory.max"), "2147483648\n").unwrap();
+        std::fs::write(cgroup.join("memory.max"), "max\n").unwrap();
+
+        // The lowest limit of the cgroup and its ancestors.
+        assert_eq!(read_limit(&mount, "/a/b/c", "memory.max"), Some(1 << 30));
+        assert_eq!(read_limit(&mount, "/a/b", "memory.max"), Some(1 << 30));
+        // A cgroup path that is not mounted falls back to the root of the mount.
+        assert_eq!(read_limit(&mount, "/x/y", "memory.max"), Some(4 << 30));
+        assert_eq!(read_limit(&mount, "/", "memory.max"), Some(4 << 30));
+        assert_eq!(read_limit(&mount, "/a/b/c", "memory.limit_in_bytes"), None);
+
+        std::fs::remove_dir_all(&mount).unwrap();
+    }
+
+    #[test]
+    fn test_heap_size_from_limit() {
+        assert_eq!(heap_size_from_limit(1000, 100, 30), (100, 700));
+        assert_eq!(heap_size_from_limit(1000, 800, 30), (700, 700));
+        assert_eq!(heap_size_from_limit(1000, 100, 0), (100, 1000));
+        // The limit is rounded down to a multiple of 100 bytes.
+        assert_eq!(heap_size_from_limit(1099, 0, 50), (0, 500));
+    }
+}
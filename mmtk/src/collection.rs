@@ -1,16 +1,49 @@
-use crate::{JuliaVM, USER_TRIGGERED_GC};
+use crate::gc_trigger::GCReason;
+use crate::JuliaVM;
 use crate::{SINGLETON, UPCALLS};
-use log::{info, trace};
+use log::{info, trace, warn};
 use mmtk::util::alloc::AllocationError;
+#[cfg(feature = "delegated_gc_trigger")]
+use mmtk::util::heap::GCTriggerPolicy;
 use mmtk::util::opaque_pointer::*;
 use mmtk::vm::{Collection, GCThreadContext};
 use mmtk::Mutator;
-use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
 
-use crate::{BLOCK_FOR_GC, STW_COND, WORLD_HAS_STOPPED};
+use crate::{BLOCK_FOR_GC, STOP_MUTATORS, STW_COND, WORLD_HAS_STOPPED};
 
 static GC_START: AtomicU64 = AtomicU64::new(0);
 
+// Time-to-safepoint: the time from a mutator requesting a GC (block_for_gc) until all the mutators
+// have stopped (mmtk_block_thread_for_gc). In nanoseconds.
+static LAST_TIME_TO_SAFEPOINT: AtomicU64 = AtomicU64::new(0);
+static MAX_TIME_TO_SAFEPOINT: AtomicU64 = AtomicU64::new(0);
+static TOTAL_TIME_TO_SAFEPOINT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // The GC controller thread, so we can join it at shutdown.
+    static ref GC_CONTROLLER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+// Whether mmtk_gc_shutdown has been called.
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+// Whether the GC controller should stop at the end of the GC. This is set for the last GC, which is
+// run by mmtk_gc_shutdown.
+static STOP_GC_CONTROLLER: AtomicBool = AtomicBool::new(false);
+
+// The payload that we unwind the GC controller with, from resume_mutators at the end of the last GC
+// to spawn_gc_thread. MMTk 0.20 has no API to stop the GC threads, and the controller loops forever.
+struct StopGCController;
+
+thread_local! {
+    // When the current mutator requested a GC. Only the mutator that wins the race to run the GC
+    // gets to mmtk_block_thread_for_gc, and it reads its own request time.
+    static BLOCK_FOR_GC_START: Cell<u64> = Cell::new(0);
+}
+
 pub struct VMCollection {}
 
 impl Collection<JuliaVM> for VMCollection {
@@ -18,11 +51,18 @@ impl Collection<JuliaVM> for VMCollection {
     where
         F: FnMut(&'static mut Mutator<JuliaVM>),
     {
+        crate::gc_stats::gc_start();
+
         // Wait for all mutators to stop and all finalizers to run
-        while !AtomicBool::load(&WORLD_HAS_STOPPED, Ordering::SeqCst) {
-            // Stay here while the world has not stopped
-            // FIXME add wait var
+        {
+            let &(ref lock, ref cvar) = &*STOP_MUTATORS.clone();
+            let mut count = lock.lock().unwrap();
+            while !AtomicBool::load(&WORLD_HAS_STOPPED, Ordering::SeqCst) {
+                count = cvar.wait(count).unwrap();
+            }
         }
+        crate::gc_trigger::start_gc_reason();
+        crate::weak_refs::prepare_soft_refs();
 
         trace!("Stopped the world!");
 
@@ -34,6 +74,7 @@ impl Collection<JuliaVM> for VMCollection {
                 mutator_visitor(mutator);
             }
         }
+        crate::gc_stats::STOP_THE_WORLD.end();
 
         // Record the start time of the GC
         let now = unsafe { ((*UPCALLS).jl_hrtime)() };
@@ -47,12 +88,22 @@ impl Collection<JuliaVM> for VMCollection {
         trace!("gc_end = {}", end);
         let gc_time = end - GC_START.load(Ordering::Relaxed);
         unsafe { ((*UPCALLS).update_gc_time)(gc_time) }
+        crate::julia_finalizer::queue_ready_finalizers();
+        crate::gc_stats::gc_end(gc_time, LAST_TIME_TO_SAFEPOINT.load(Ordering::Relaxed));
+        crate::finalizer_thread::notify();
 
-        AtomicBool::store(&BLOCK_FOR_GC, false, Ordering::SeqCst);
-        AtomicBool::store(&WORLD_HAS_STOPPED, false, Ordering::SeqCst);
+        crate::gc_trigger::reset_malloc_growth();
+        crate::weak_refs::check_heap_occupancy();
 
-        let &(_, ref cvar) = &*STW_COND.clone();
-        cvar.notify_all();
+        {
+            // Hold the lock, so the mutator cannot miss the notification between checking
+            // BLOCK_FOR_GC and waiting.
+            let &(ref lock, ref cvar) = &*STW_COND.clone();
+            let _count = lock.lock().unwrap();
+            AtomicBool::store(&BLOCK_FOR_GC, false, Ordering::SeqCst);
+            AtomicBool::store(&WORLD_HAS_STOPPED, false, Ordering::SeqCst);
+            cvar.notify_all();
+        }
 
         info!(
             "Live bytes = {}, total bytes = {}",
@@ -61,45 +112,142 @@ impl Collection<JuliaVM> for VMCollection {
         );
 
         trace!("Resuming mutators.");
+
+        if STOP_GC_CONTROLLER.load(Ordering::SeqCst) {
+            // resume_mutators is the last thing the controller does for a GC, so we can leave the
+            // controller loop here. This does not call the panic hook.
+            std::panic::resume_unwind(Box::new(StopGCController));
+        }
     }
 
     fn block_for_gc(_tls: VMMutatorThread) {
         info!("Triggered GC!");
 
-        unsafe { ((*UPCALLS).prepare_to_collect)() };
+        let now = unsafe { ((*UPCALLS).jl_hrtime)() };
+        BLOCK_FOR_GC_START.with(|start| start.set(now));
+
+        // Julia refuses to start the GC if collection is disabled. Then the GC is deferred, and we
+        // request it again when collection is enabled. If we cannot defer it, collection has been
+        // enabled in the meantime, and we try again.
+        let reason = crate::gc_trigger::pending_gc_reason();
+        while !unsafe { ((*UPCALLS).prepare_to_collect)(reason) } {
+            if crate::gc_trigger::defer_gc() {
+                break;
+            }
+            std::thread::yield_now();
+        }
+        BLOCK_FOR_GC_START.with(|start| start.set(0));
 
         info!("Finished blocking mutator for GC!");
     }
 
     fn spawn_gc_thread(_tls: VMThread, ctx: GCThreadContext<JuliaVM>) {
-        // Just drop the join handle. The thread will run until the process quits.
-        let _ = std::thread::spawn(move || {
-            use mmtk::util::opaque_pointer::*;
-            use mmtk::util::Address;
-            let worker_tls = VMWorkerThread(VMThread(OpaquePointer::from_address(unsafe {
-                Address::from_usize(thread_id::get())
-            })));
-            match ctx {
-                GCThreadContext::Controller(mut c) => {
-                    mmtk::memory_manager::start_control_collector(&SINGLETON, worker_tls, &mut c)
-                }
-                GCThreadContext::Worker(mut w) => {
-                    mmtk::memory_manager::start_worker(&SINGLETON, worker_tls, &mut w)
+        let name = match &ctx {
+            GCThreadContext::Controller(_) => "mmtk-gc-ctrl".to_string(),
+            GCThreadContext::Worker(w) => format!("mmtk-gc-worker-{}", w.ordinal),
+        };
+        let is_controller = matches!(ctx, GCThreadContext::Controller(_));
+        let handle = std::thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                use mmtk::util::opaque_pointer::*;
+                use mmtk::util::Address;
+                configure_gc_thread();
+                let worker_tls = VMWorkerThread(VMThread(OpaquePointer::from_address(unsafe {
+                    Address::from_usize(thread_id::get())
+                })));
+                match ctx {
+                    GCThreadContext::Controller(mut c) => {
+                        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                            mmtk::memory_manager::start_control_collector(
+                                &SINGLETON, worker_tls, &mut c,
+                            )
+                        }));
+                        if let Err(payload) = result {
+                            if !payload.is::<StopGCController>() {
+                                std::panic::resume_unwind(payload);
+                            }
+                            info!("Stopped the GC controller");
+                        }
+                    }
+                    GCThreadContext::Worker(mut w) => {
+                        mmtk::memory_manager::start_worker(&SINGLETON, worker_tls, &mut w)
+                    }
                 }
-            }
-        });
+            })
+            .expect("Failed to spawn a GC thread");
+        // Keep the join handle of the controller, so we can join it at shutdown.
+        if is_controller {
+            *GC_CONTROLLER.lock().unwrap() = Some(handle);
+        }
     }
 
-    fn schedule_finalization(_tls: VMWorkerThread) {}
+    fn schedule_finalization(_tls: VMWorkerThread) {
+        crate::julia_finalizer::schedule_finalization();
+    }
 
-    fn out_of_memory(_tls: VMThread, _err_kind: AllocationError) {
-        println!("Out of Memory!");
-        unsafe { ((*UPCALLS).jl_throw_out_of_memory_error)() };
+    fn out_of_memory(tls: VMThread, err_kind: AllocationError) {
+        crate::oom::out_of_memory(tls, err_kind);
     }
 
     fn vm_live_bytes() -> usize {
         crate::api::JULIA_MALLOC_BYTES.load(Ordering::SeqCst)
     }
+
+    #[cfg(feature = "delegated_gc_trigger")]
+    fn create_gc_trigger() -> Box<dyn GCTriggerPolicy<JuliaVM>> {
+        Box::<crate::gc_trigger::JuliaGCTrigger>::default()
+    }
+}
+
+/// Set the CPU affinity and the nice level of the current GC thread from the binding options. A
+/// failure is reported and the thread runs with what it inherited.
+#[cfg(target_os = "linux")]
+fn configure_gc_thread() {
+    let options = crate::options::JULIA_OPTIONS.read().unwrap().clone();
+
+    if let Some(cpus) = options.gc_thread_cpus {
+        let result = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in cpus {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if result != 0 {
+            warn!(
+                "Cannot set the CPU affinity of a GC thread: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    if let Some(nice) = options.gc_thread_nice {
+        // On Linux, the nice level is per thread, and we set it with the thread ID.
+        let result = unsafe {
+            let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+            libc::setpriority(libc::PRIO_PROCESS, tid, nice)
+        };
+        if result != 0 {
+            warn!(
+                "Cannot set the nice level of a GC thread: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// The CPU affinity and the nice level of a thread can only be set on Linux. Elsewhere, we report
+/// once that the options are ignored.
+#[cfg(not(target_os = "linux"))]
+fn configure_gc_thread() {
+    static WARN_ONCE: std::sync::Once = std::sync::Once::new();
+    let options = crate::options::JULIA_OPTIONS.read().unwrap().clone();
+    if options.gc_thread_cpus.is_some() || options.gc_thread_nice.is_some() {
+        WARN_ONCE.call_once(|| {
+            warn!("julia_gc_thread_cpus and julia_gc_thread_nice are only supported on Linux")
+        });
+    }
 }
 
 pub fn is_current_gc_nursery() -> bool {
@@ -113,16 +261,97 @@ pub fn is_current_gc_nursery() -> bool {
 pub extern "C" fn mmtk_block_thread_for_gc() {
     AtomicBool::store(&BLOCK_FOR_GC, true, Ordering::SeqCst);
 
+    info!("Blocking for GC!");
+
+    // All the mutators have reached a safepoint.
+    let now = unsafe { ((*UPCALLS).jl_hrtime)() };
+    let start = BLOCK_FOR_GC_START.with(|start| start.replace(0));
+    if start != 0 {
+        let time_to_safepoint = now.saturating_sub(start);
+        trace!("time_to_safepoint = {}", time_to_safepoint);
+        LAST_TIME_TO_SAFEPOINT.store(time_to_safepoint, Ordering::Relaxed);
+        MAX_TIME_TO_SAFEPOINT.fetch_max(time_to_safepoint, Ordering::Relaxed);
+        TOTAL_TIME_TO_SAFEPOINT.fetch_add(time_to_safepoint, Ordering::Relaxed);
+    }
+
+    // The callbacks run without the lock of STW_COND, which threads that exit wait for in
+    // mmtk_destroy_mutator. The GC does not start before we tell the controller below.
+    crate::gc_callbacks::invoke_pre_gc_callbacks(crate::gc_trigger::is_full_gc_requested());
+
     let &(ref lock, ref cvar) = &*STW_COND.clone();
     let mut count = lock.lock().unwrap();
 
-    info!("Blocking for GC!");
-
-    AtomicBool::store(&WORLD_HAS_STOPPED, true, Ordering::SeqCst);
+    // Tell the GC controller that the world has stopped.
+    {
+        let &(ref lock, ref cvar) = &*STOP_MUTATORS.clone();
+        let _count = lock.lock().unwrap();
+        AtomicBool::store(&WORLD_HAS_STOPPED, true, Ordering::SeqCst);
+        cvar.notify_all();
+    }
 
     while AtomicBool::load(&BLOCK_FOR_GC, Ordering::SeqCst) {
         count = cvar.wait(count).unwrap();
     }
+    drop(count);
+
+    crate::gc_callbacks::invoke_post_gc_callbacks(!crate::gc_stats::last_gc_stats().is_nursery);
+}
+
+/// Shut down the GC. This is called once by a mutator when Julia exits, after the finalizers have
+/// run, and when no other mutator runs Julia code. No Julia code can run after this. Later calls do
+/// nothing.
+/// * We stop the finalizer thread, and wait for the GC in progress to finish.
+/// * We run one last GC, and stop the GC controller at the end of it, and join it. MMTk 0.20 cannot
+///   stop the GC workers. They wait for the controller, and stay parked without doing any work.
+/// * We disable collection for good, and flush the GC log.
+/// * We destroy the mutators that are still bound, as if their threads exited.
+#[no_mangle]
+pub extern "C" fn mmtk_gc_shutdown() {
+    if SHUT_DOWN.swap(true, Ordering::SeqCst) || !crate::MMTK_INITIALIZED.load(Ordering::SeqCst) {
+        return;
+    }
 
-    AtomicIsize::store(&USER_TRIGGERED_GC, 0, Ordering::SeqCst);
+    crate::finalizer_thread::shutdown();
+    {
+        let &(ref lock, ref cvar) = &*STW_COND.clone();
+        let mut count = lock.lock().unwrap();
+        while AtomicBool::load(&BLOCK_FOR_GC, Ordering::SeqCst) {
+            count = cvar.wait(count).unwrap();
+        }
+    }
+
+    // The last GC cannot run if collection is disabled. Then the controller keeps waiting for a GC
+    // request, which never comes.
+    if crate::gc_trigger::is_collection_disabled() {
+        warn!("Collection is disabled at shutdown, the GC controller is not stopped");
+    } else {
+        let tls = VMMutatorThread(VMThread(unsafe { ((*UPCALLS).get_current_ptls)() }));
+        STOP_GC_CONTROLLER.store(true, Ordering::SeqCst);
+        crate::gc_trigger::request_gc(tls, GCReason::UserIncremental, false);
+        if let Some(controller) = GC_CONTROLLER.lock().unwrap().take() {
+            if controller.join().is_err() {
+                warn!("The GC controller panicked");
+            }
+        }
+    }
+    crate::gc_trigger::disable_collection();
+
+    crate::gc_log::close();
+    info!("Last GC: {:?}", crate::gc_stats::last_gc_stats());
+
+    let mutators = crate::active_plan::mutators_snapshot();
+    info!("Destroying {} mutators", mutators.len());
+    for mutator in mutators {
+        crate::api::mmtk_destroy_mutator(mutator);
+    }
+}
+
+/// Get the time-to-safepoint of the last GC, the max and the total of all GCs, in nanoseconds.
+#[no_mangle]
+pub extern "C" fn mmtk_get_time_to_safepoint(last: *mut u64, max: *mut u64, total: *mut u64) {
+    unsafe {
+        *last = LAST_TIME_TO_SAFEPOINT.load(Ordering::Relaxed);
+        *max = MAX_TIME_TO_SAFEPOINT.load(Ordering::Relaxed);
+        *total = TOTAL_TIME_TO_SAFEPOINT.load(Ordering::Relaxed);
+    }
 }
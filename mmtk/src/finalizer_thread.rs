@@ -0,0 +1,133 @@
+use crate::UPCALLS;
+use log::*;
+use std::sync::{Condvar, Mutex};
+
+/// The state of the finalizer thread. We count the requests to run finalizers, so a thread that
+/// waits for finalizers can tell when a run that started after its request has finished.
+#[derive(Default)]
+struct FinalizerThreadState {
+    running: bool,
+    shutting_down: bool,
+    /// The number of requests so far.
+    requested: u64,
+    /// The requests that the finalizer thread has started to run finalizers for.
+    started: u64,
+    /// The requests that the finalizer thread has run finalizers for.
+    completed: u64,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<FinalizerThreadState> = Mutex::new(FinalizerThreadState::default());
+    // Notified when there are finalizers to run, or when the thread should stop.
+    static ref WORK: Condvar = Condvar::new();
+    // Notified when the finalizer thread has run finalizers, or when it stops.
+    static ref DONE: Condvar = Condvar::new();
+}
+
+/// Whether the finalizer thread is running. If it is, the mutators do not run finalizers after a
+/// GC, and leave them to the finalizer thread.
+pub fn is_running() -> bool {
+    STATE.lock().unwrap().running
+}
+
+/// Start the finalizer thread if `julia_finalizer_thread` is set. The thread is adopted by Julia in
+/// the `run_finalizer_thread` upcall, so this has to be called after Julia is initialized. Returns
+/// whether the finalizer thread is running.
+pub fn start() -> bool {
+    if !crate::options::JULIA_OPTIONS
+        .read()
+        .unwrap()
+        .finalizer_thread
+    {
+        return false;
+    }
+
+    let mut state = STATE.lock().unwrap();
+    if state.running || state.shutting_down {
+        return state.running;
+    }
+    let spawned = std::thread::Builder::new()
+        .name("mmtk-finalizer".to_string())
+        .spawn(|| {
+            unsafe { ((*UPCALLS).run_finalizer_thread)() };
+            STATE.lock().unwrap().running = false;
+            DONE.notify_all();
+        });
+    match spawned {
+        Ok(_) => state.running = true,
+        Err(error) => warn!("Cannot spawn the finalizer thread: {}", error),
+    }
+    state.running
+}
+
+/// Wake the finalizer thread if there are finalizers to run. This is called when a GC is done.
+pub fn notify() {
+    let mut state = STATE.lock().unwrap();
+    if state.running && crate::julia_finalizer::num_queued_finalizers() > 0 {
+        state.requested += 1;
+        WORK.notify_one();
+    }
+}
+
+/// Stop the finalizer thread when it finishes the finalizers it is running. We do not wait for it,
+/// as it may be waiting for a GC.
+pub fn shutdown() {
+    STATE.lock().unwrap().shutting_down = true;
+    WORK.notify_all();
+    DONE.notify_all();
+}
+
+/// Called by the finalizer thread in a GC safe region. Block until there are finalizers to run, and
+/// return true, or until the thread should stop, and return false.
+#[no_mangle]
+pub extern "C" fn mmtk_finalizer_thread_wait_for_work() -> bool {
+    let mut state = STATE.lock().unwrap();
+    while state.started == state.requested && !state.shutting_down {
+        state = WORK.wait(state).unwrap();
+    }
+    state.started = state.requested;
+    !state.shutting_down
+}
+
+/// Called by the finalizer thread when it has run the finalizers.
+#[no_mangle]
+pub extern "C" fn mmtk_finalizer_thread_done() {
+    let mut state = STATE.lock().unwrap();
+    state.completed = state.started;
+    DONE.notify_all();
+}
+
+#[no_mangle]
+pub extern "C" fn mmtk_start_finalizer_thread() -> bool {
+    start()
+}
+
+#[no_mangle]
+pub extern "C" fn mmtk_finalizer_thread_running() -> bool {
+    is_running()
+}
+
+/// Wait until the finalizer thread has run the finalizers that are pending. This should be called
+/// in a GC safe region, as the finalizers may trigger a GC. Returns false if the finalizer thread is
+/// not running, in which case the caller should run the finalizers itself.
+#[no_mangle]
+pub extern "C" fn mmtk_wait_for_finalizers() -> bool {
+    let mut state = STATE.lock().unwrap();
+    if !state.running {
+        return false;
+    }
+    state.requested += 1;
+    let request = state.requested;
+    WORK.notify_one();
+    while state.completed < request && state.running && !state.shutting_down {
+        state = DONE.wait(state).unwrap();
+    }
+    state.completed >= request
+}
+
+/// The number of finalizers that are waiting to run. This reads the length of the list that the
+/// finalizer thread is draining, so it is only a snapshot.
+#[no_mangle]
+pub extern "C" fn mmtk_finalizer_queue_depth() -> usize {
+    crate::julia_finalizer::num_queued_finalizers()
+}
@@ -0,0 +1,68 @@
+use std::sync::RwLock;
+
+/// A callback that is called before or after a GC, with whether the GC is a full heap GC. This is
+/// the same as `jl_gc_cb_pre_gc_t` and `jl_gc_cb_post_gc_t` in Julia.
+pub type GCCallback = extern "C" fn(full: i32);
+
+// The callbacks are called by the mutator that triggered the GC, in mmtk_block_thread_for_gc: the
+// pre-GC callbacks after all the mutators have stopped and before the GC starts, and the post-GC
+// callbacks after the GC is done and the mutators have been released, so the other mutators may
+// already be running. This is also when Julia's stock GC calls them, after the safepoint ends.
+lazy_static! {
+    static ref PRE_GC_CALLBACKS: RwLock<Vec<GCCallback>> = RwLock::new(vec![]);
+    static ref POST_GC_CALLBACKS: RwLock<Vec<GCCallback>> = RwLock::new(vec![]);
+}
+
+// See MMTk_GCCallbackKind in mmtk.h
+fn callbacks(kind: u8) -> Option<&'static RwLock<Vec<GCCallback>>> {
+    match kind {
+        0 => Some(&PRE_GC_CALLBACKS),
+        1 => Some(&POST_GC_CALLBACKS),
+        _ => None,
+    }
+}
+
+fn invoke(callbacks: &RwLock<Vec<GCCallback>>, full: bool) {
+    // Copy the list, so a callback can register or unregister callbacks.
+    let callbacks = callbacks.read().unwrap().clone();
+    for callback in callbacks {
+        callback(full as i32);
+    }
+}
+
+pub fn invoke_pre_gc_callbacks(full: bool) {
+    invoke(&PRE_GC_CALLBACKS, full);
+}
+
+pub fn invoke_post_gc_callbacks(full: bool) {
+    invoke(&POST_GC_CALLBACKS, full);
+}
+
+/// Register a callback of the kind (0 for pre-GC, 1 for post-GC). A callback is only registered
+/// once. Returns false if the kind is unknown.
+#[no_mangle]
+pub extern "C" fn mmtk_register_gc_callback(kind: u8, callback: GCCallback) -> bool {
+    let callbacks = match callbacks(kind) {
+        Some(callbacks) => callbacks,
+        None => return false,
+    };
+    let mut callbacks = callbacks.write().unwrap();
+    if !callbacks.iter().any(|cb| *cb as usize == callback as usize) {
+        callbacks.push(callback);
+    }
+    true
+}
+
+/// Unregister a callback of the kind. Returns false if the kind is unknown.
+#[no_mangle]
+pub extern "C" fn mmtk_unregister_gc_callback(kind: u8, callback: GCCallback) -> bool {
+    let callbacks = match callbacks(kind) {
+        Some(callbacks) => callbacks,
+        None => return false,
+    };
+    callbacks
+        .write()
+        .unwrap()
+        .retain(|cb| *cb as usize != callback as usize);
+    true
+}
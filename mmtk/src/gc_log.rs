@@ -0,0 +1,85 @@
+use crate::gc_stats::GCStats;
+use crate::SINGLETON;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable with the path of the GC event log. If it is set, we append one JSON line
+/// for each GC to the file. This does not depend on the log level, so it works in release builds.
+pub const GC_LOG_ENV_VAR: &str = "MMTK_JULIA_GC_LOG";
+
+lazy_static! {
+    static ref GC_LOG: Mutex<Option<File>> = Mutex::new(None);
+}
+
+/// Open the log file if `MMTK_JULIA_GC_LOG` is set. This is called when MMTk is initialized.
+pub fn init() {
+    let path = match std::env::var(GC_LOG_ENV_VAR) {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => *GC_LOG.lock().unwrap() = Some(file),
+        Err(error) => eprintln!("[MMTk] Cannot open GC log {:?}: {}", path, error),
+    }
+}
+
+/// Write a line for the GC. If the write fails, we report the error and stop logging.
+pub fn log_gc(stats: &GCStats) {
+    let mut log = GC_LOG.lock().unwrap();
+    let file = match log.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+
+    if let Err(error) = file.write_all(format_gc(stats).as_bytes()) {
+        eprintln!("[MMTk] Cannot write GC log: {}", error);
+        *log = None;
+    }
+}
+
+/// Flush and close the log file. This is called when the GC shuts down.
+pub fn close() {
+    if let Some(file) = GC_LOG.lock().unwrap().take() {
+        if let Err(error) = file.sync_all() {
+            eprintln!("[MMTk] Cannot flush GC log: {}", error);
+        }
+    }
+}
+
+// All the values are numbers or fixed names, so we do not need to escape anything.
+fn format_gc(stats: &GCStats) -> String {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or(0);
+    format!(
+        concat!(
+            "{{\"gc\":{},\"timestamp_ms\":{},\"plan\":\"{:?}\",\"kind\":\"{}\",\"trigger\":\"{}\",",
+            "\"used_bytes_before\":{},\"used_bytes_after\":{},\"vm_live_bytes\":{},",
+            "\"pause_ns\":{},\"time_to_safepoint_ns\":{},\"mutators\":{},\"finalizers_queued\":{},",
+            "\"phases_ns\":{{\"stop_the_world\":{},\"mutator_roots\":{},\"vm_roots\":{},",
+            "\"closure\":{},\"finalizers\":{},\"sweep_malloced_arrays\":{},\"release\":{}}}}}\n"
+        ),
+        stats.gc_count,
+        timestamp_ms,
+        *SINGLETON.get_options().plan,
+        if stats.is_nursery { "nursery" } else { "full" },
+        stats.reason.name(),
+        stats.used_bytes_before,
+        stats.used_bytes_after,
+        stats.vm_live_bytes,
+        stats.total_time,
+        stats.time_to_safepoint,
+        stats.mutators,
+        stats.finalizers_queued,
+        stats.stop_the_world,
+        stats.mutator_roots,
+        stats.vm_roots,
+        stats.closure,
+        stats.finalizers,
+        stats.sweep_malloced_arrays,
+        stats.release,
+    )
+}
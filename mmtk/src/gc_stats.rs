@@ -0,0 +1,203 @@
+use crate::gc_trigger::GCReason;
+use crate::{JuliaVM, SINGLETON, UPCALLS};
+use log::*;
+use mmtk::memory_manager;
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::MMTK;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Statistics of a GC. All the times are in nanoseconds. A phase may run on several GC threads,
+/// and its time is from when the first thread starts it to when the last thread finishes it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GCStats {
+    /// The number of GCs so far, including this one.
+    pub gc_count: u64,
+    /// Whether this is a nursery GC.
+    pub is_nursery: bool,
+    /// Why the GC was triggered.
+    pub reason: GCReason,
+    /// The bytes used by the MMTk heap before and after the GC.
+    pub used_bytes_before: u64,
+    pub used_bytes_after: u64,
+    /// The bytes that Julia allocated with malloc, after the GC.
+    pub vm_live_bytes: u64,
+    /// The number of mutators.
+    pub mutators: u64,
+    /// The number of finalizers that are waiting to run after the GC.
+    pub finalizers_queued: u64,
+    /// The time from stopping the world to resuming it. This is the time reported to Julia.
+    pub total_time: u64,
+    /// The time from a mutator requesting the GC until all the mutators stopped.
+    pub time_to_safepoint: u64,
+    /// The time the GC controller spent waiting for the mutators to stop and visiting them.
+    pub stop_the_world: u64,
+    /// Scanning the roots of the mutator threads.
+    pub mutator_roots: u64,
+    /// Scanning the VM-specific roots.
+    pub vm_roots: u64,
+    /// The transitive closure, from the `Closure` bucket until the `VMRefClosure` bucket opens.
+    pub closure: u64,
+    /// Sweeping and tracing the finalizer lists (`ScanFinalizers`).
+    pub finalizers: u64,
+    /// Sweeping the malloc'd arrays (`SweepMallocedArrays`).
+    pub sweep_malloced_arrays: u64,
+    /// From the `Release` bucket to resuming the mutators.
+    pub release: u64,
+}
+
+/// Records when a phase starts and ends in a GC.
+pub struct PhaseTimer {
+    start: AtomicU64,
+    end: AtomicU64,
+}
+
+impl PhaseTimer {
+    const fn new() -> Self {
+        PhaseTimer {
+            start: AtomicU64::new(u64::MAX),
+            end: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        self.start.store(u64::MAX, Ordering::Relaxed);
+        self.end.store(0, Ordering::Relaxed);
+    }
+
+    pub fn start(&self) {
+        self.start.fetch_min(now(), Ordering::Relaxed);
+    }
+
+    pub fn end(&self) {
+        self.end.fetch_max(now(), Ordering::Relaxed);
+    }
+
+    /// Time a piece of work in the phase.
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.start();
+        let result = f();
+        self.end();
+        result
+    }
+
+    /// The time of the phase, or 0 if the phase did not run.
+    fn elapsed(&self) -> u64 {
+        self.end
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.start.load(Ordering::Relaxed))
+    }
+}
+
+pub static STOP_THE_WORLD: PhaseTimer = PhaseTimer::new();
+pub static MUTATOR_ROOTS: PhaseTimer = PhaseTimer::new();
+pub static VM_ROOTS: PhaseTimer = PhaseTimer::new();
+pub static CLOSURE: PhaseTimer = PhaseTimer::new();
+pub static FINALIZERS: PhaseTimer = PhaseTimer::new();
+pub static SWEEP_MALLOCED_ARRAYS: PhaseTimer = PhaseTimer::new();
+pub static RELEASE: PhaseTimer = PhaseTimer::new();
+
+static PHASES: [&PhaseTimer; 7] = [
+    &STOP_THE_WORLD,
+    &MUTATOR_ROOTS,
+    &VM_ROOTS,
+    &CLOSURE,
+    &FINALIZERS,
+    &SWEEP_MALLOCED_ARRAYS,
+    &RELEASE,
+];
+
+static GC_COUNT: AtomicU64 = AtomicU64::new(0);
+static USED_BYTES_BEFORE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref LAST_GC_STATS: Mutex<GCStats> = Mutex::new(GCStats::default());
+}
+
+fn now() -> u64 {
+    unsafe { ((*UPCALLS).jl_hrtime)() }
+}
+
+/// Called by the GC controller when a GC starts, before stopping the world. Some phases are
+/// timed from the buckets, so we put marker packets in the buckets that start or end the phases.
+pub fn gc_start() {
+    for phase in PHASES.iter() {
+        phase.reset();
+    }
+    STOP_THE_WORLD.start();
+    USED_BYTES_BEFORE.store(
+        memory_manager::used_bytes(&SINGLETON) as u64,
+        Ordering::Relaxed,
+    );
+
+    memory_manager::add_work_packet(
+        &SINGLETON,
+        WorkBucketStage::Closure,
+        PhaseMarker::Start(&CLOSURE),
+    );
+    memory_manager::add_work_packet(
+        &SINGLETON,
+        WorkBucketStage::VMRefClosure,
+        PhaseMarker::End(&CLOSURE),
+    );
+    memory_manager::add_work_packet(
+        &SINGLETON,
+        WorkBucketStage::Release,
+        PhaseMarker::Start(&RELEASE),
+    );
+}
+
+/// Called when resuming the mutators, with the times that are measured elsewhere.
+pub fn gc_end(total_time: u64, time_to_safepoint: u64) {
+    use crate::mmtk::vm::{ActivePlan, Collection};
+    RELEASE.end();
+
+    let stats = GCStats {
+        gc_count: GC_COUNT.fetch_add(1, Ordering::Relaxed) + 1,
+        is_nursery: crate::collection::is_current_gc_nursery(),
+        reason: crate::gc_trigger::current_gc_reason(),
+        used_bytes_before: USED_BYTES_BEFORE.load(Ordering::Relaxed),
+        used_bytes_after: memory_manager::used_bytes(&SINGLETON) as u64,
+        vm_live_bytes: crate::collection::VMCollection::vm_live_bytes() as u64,
+        mutators: crate::active_plan::VMActivePlan::number_of_mutators() as u64,
+        finalizers_queued: crate::julia_finalizer::num_queued_finalizers() as u64,
+        total_time,
+        time_to_safepoint,
+        stop_the_world: STOP_THE_WORLD.elapsed(),
+        mutator_roots: MUTATOR_ROOTS.elapsed(),
+        vm_roots: VM_ROOTS.elapsed(),
+        closure: CLOSURE.elapsed(),
+        finalizers: FINALIZERS.elapsed(),
+        sweep_malloced_arrays: SWEEP_MALLOCED_ARRAYS.elapsed(),
+        release: RELEASE.elapsed(),
+    };
+    debug!("{:?}", stats);
+    *LAST_GC_STATS.lock().unwrap() = stats;
+    crate::gc_log::log_gc(&stats);
+}
+
+pub fn last_gc_stats() -> GCStats {
+    *LAST_GC_STATS.lock().unwrap()
+}
+
+/// A work packet that marks the start or the end of a phase when its bucket opens.
+enum PhaseMarker {
+    Start(&'static PhaseTimer),
+    End(&'static PhaseTimer),
+}
+
+impl GCWork<JuliaVM> for PhaseMarker {
+    fn do_work(&mut self, _worker: &mut GCWorker<JuliaVM>, _mmtk: &'static MMTK<JuliaVM>) {
+        match self {
+            PhaseMarker::Start(phase) => phase.start(),
+            PhaseMarker::End(phase) => phase.end(),
+        }
+    }
+}
+
+/// Copy the statistics of the last GC to `stats`.
+#[no_mangle]
+pub extern "C" fn mmtk_last_gc_stats(stats: *mut GCStats) {
+    unsafe { *stats = last_gc_stats() };
+}
@@ -0,0 +1,398 @@
+use crate::collection::VMCollection;
+#[cfg(feature = "delegated_gc_trigger")]
+use crate::JuliaVM;
+use crate::SINGLETON;
+use log::*;
+use mmtk::memory_manager;
+#[cfg(feature = "delegated_gc_trigger")]
+use mmtk::util::conversions;
+#[cfg(feature = "delegated_gc_trigger")]
+use mmtk::util::heap::{GCTriggerPolicy, SpaceStats};
+use mmtk::util::opaque_pointer::*;
+use mmtk::util::options::{GCTriggerSelector, Options};
+use mmtk::vm::Collection;
+#[cfg(feature = "delegated_gc_trigger")]
+use mmtk::{Plan, MMTK};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// The heap limits in bytes. A MIN_HEAP_SIZE of 0 means a fixed heap size, like the arguments of
+// mmtk_gc_init. MMTk decides its GC trigger once at initialization, so the limits can only be
+// changed while Julia is running if the user asks for the delegated trigger (JuliaGCTrigger), which
+// reads them from here.
+static MIN_HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+static MAX_HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+// Whether MMTk uses JuliaGCTrigger.
+static DELEGATED: AtomicBool = AtomicBool::new(false);
+
+// Malloc'd memory is not in the MMTk heap, and a program that mostly allocates malloc'd arrays can
+// grow without filling the heap. We trigger a collection when the malloc'd memory has grown by
+// MALLOC_THRESHOLD bytes since the last GC.
+static MALLOC_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+static MALLOC_BYTES_AFTER_LAST_GC: AtomicUsize = AtomicUsize::new(0);
+
+/// Why a GC is triggered. This is passed to Julia in the `prepare_to_collect` upcall, and kept in
+/// the GC stats. See MMTk_GCReason in mmtk.h.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum GCReason {
+    /// MMTk triggered the GC because the heap is full.
+    #[default]
+    HeapFull = 0,
+    /// The user requested a GC with `JL_GC_AUTO`.
+    UserAuto = 1,
+    /// The user requested a GC with `JL_GC_FULL`, e.g. `GC.gc()`.
+    UserFull = 2,
+    /// The user requested a GC with `JL_GC_INCREMENTAL`, e.g. `GC.gc(false)`.
+    UserIncremental = 3,
+    /// The malloc'd memory has grown over the threshold. See `poll_malloc`.
+    Malloc = 4,
+    /// MMTk could not satisfy an allocation after a GC, and collects everything it can before
+    /// reporting that the heap is out of memory. See `oom.rs`.
+    Emergency = 5,
+}
+
+impl GCReason {
+    fn from_usize(value: usize) -> GCReason {
+        match value {
+            0 => GCReason::HeapFull,
+            1 => GCReason::UserAuto,
+            2 => GCReason::UserFull,
+            3 => GCReason::UserIncremental,
+            4 => GCReason::Malloc,
+            5 => GCReason::Emergency,
+            _ => panic!("Unknown value: {}", value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GCReason::HeapFull => "heap_full",
+            GCReason::UserAuto => "user_auto",
+            GCReason::UserFull => "user_full",
+            GCReason::UserIncremental => "user_incremental",
+            GCReason::Malloc => "malloc",
+            GCReason::Emergency => "emergency",
+        }
+    }
+}
+
+// The reason for the GC that is requested but not started yet. If MMTk triggers a GC by itself, no
+// reason is set, and it is a HeapFull GC.
+static PENDING_GC_REASON: AtomicUsize = AtomicUsize::new(GCReason::HeapFull as usize);
+// The reason for the current (or the last) GC.
+static CURRENT_GC_REASON: AtomicUsize = AtomicUsize::new(GCReason::HeapFull as usize);
+
+// Whether the pending GC is requested to be a full heap GC.
+static FULL_GC_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set the reason for the GC that we are about to request.
+pub fn set_pending_gc_reason(reason: GCReason) {
+    PENDING_GC_REASON.store(reason as usize, Ordering::SeqCst);
+}
+
+/// Request a GC for the reason. The GC is triggered by `handle_user_collection_request`, so the
+/// mutator blocks until the GC is done. If collection is disabled, the GC is deferred until it is
+/// enabled again.
+pub fn request_gc(tls: VMMutatorThread, reason: GCReason, exhaustive: bool) {
+    set_pending_gc_reason(reason);
+    if defer_gc() {
+        return;
+    }
+    FULL_GC_REQUESTED.store(exhaustive, Ordering::SeqCst);
+    SINGLETON.handle_user_collection_request(tls, true, exhaustive);
+}
+
+/// Whether the GC that is starting is a full heap GC, as far as we know before MMTk decides. This is
+/// exact for non-generational plans. For generational plans, it is true if a full heap GC was
+/// requested, though MMTk may still decide to do a full heap GC otherwise. This is called once for
+/// each GC.
+pub fn is_full_gc_requested() -> bool {
+    let requested = FULL_GC_REQUESTED.swap(false, Ordering::SeqCst);
+    requested || SINGLETON.get_plan().generational().is_none()
+}
+
+/// The reason for the GC that is requested. This is used by the mutator that blocks for the GC.
+pub fn pending_gc_reason() -> GCReason {
+    GCReason::from_usize(PENDING_GC_REASON.load(Ordering::SeqCst))
+}
+
+/// Take the reason for the GC that is starting. This is called once for each GC, after the world
+/// has stopped, so the mutator that triggered the GC has seen the pending reason.
+pub fn start_gc_reason() -> GCReason {
+    let mut reason = PENDING_GC_REASON.swap(GCReason::HeapFull as usize, Ordering::SeqCst);
+    // MMTk decides by itself to do an emergency GC, when a GC failed to free enough memory.
+    if SINGLETON.get_plan().is_emergency_collection() {
+        reason = GCReason::Emergency as usize;
+    }
+    CURRENT_GC_REASON.store(reason, Ordering::SeqCst);
+    GCReason::from_usize(reason)
+}
+
+pub fn current_gc_reason() -> GCReason {
+    GCReason::from_usize(CURRENT_GC_REASON.load(Ordering::SeqCst))
+}
+
+// Collection can be disabled in a nested way, like jl_gc_disable_counter in Julia: it is only
+// enabled again when every disable is matched by an enable. A GC that is triggered while collection
+// is disabled is deferred, and runs as soon as the counter drops to zero.
+lazy_static! {
+    static ref GC_DISABLE_COUNTER: Mutex<usize> = Mutex::new(0);
+}
+// Whether a GC was refused while collection was disabled: either the binding did not request it, or
+// Julia did not start it in `prepare_to_collect`. The reason is in PENDING_GC_REASON.
+static DEFERRED_GC: AtomicBool = AtomicBool::new(false);
+// The heap size when collection was disabled.
+static USED_BYTES_AT_DISABLE: AtomicUsize = AtomicUsize::new(0);
+// The bytes allocated while collection was disabled, in total.
+static DEFERRED_ALLOC_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_collection_disabled() -> bool {
+    *GC_DISABLE_COUNTER.lock().unwrap() != 0
+}
+
+/// Record a GC that cannot run because collection is disabled. Returns false if collection is
+/// enabled, and the GC should run now. This is checked under the lock of the counter, so a GC is
+/// never deferred after `enable_collection` has looked for the deferred GC.
+pub fn defer_gc() -> bool {
+    let counter = GC_DISABLE_COUNTER.lock().unwrap();
+    if *counter == 0 {
+        return false;
+    }
+    info!(
+        "Collection is disabled, deferring GC: {:?}",
+        pending_gc_reason()
+    );
+    DEFERRED_GC.store(true, Ordering::SeqCst);
+    true
+}
+
+/// Disable collection. This can be nested.
+pub fn disable_collection() {
+    let mut counter = GC_DISABLE_COUNTER.lock().unwrap();
+    if *counter == 0 {
+        USED_BYTES_AT_DISABLE.store(memory_manager::used_bytes(&SINGLETON), Ordering::SeqCst);
+        memory_manager::disable_collection(&SINGLETON);
+    }
+    *counter += 1;
+}
+
+/// Enable collection for a previous disable. When collection is enabled again, we account the
+/// allocation while it was disabled, and run the GC that was deferred.
+pub fn enable_collection(tls: VMMutatorThread) {
+    {
+        let mut counter = GC_DISABLE_COUNTER.lock().unwrap();
+        if *counter == 0 {
+            warn!("Collection is enabled without being disabled");
+            return;
+        }
+        *counter -= 1;
+        if *counter != 0 {
+            return;
+        }
+        let used_bytes = memory_manager::used_bytes(&SINGLETON);
+        let deferred_alloc =
+            used_bytes.saturating_sub(USED_BYTES_AT_DISABLE.load(Ordering::SeqCst));
+        DEFERRED_ALLOC_BYTES.fetch_add(deferred_alloc, Ordering::SeqCst);
+        memory_manager::enable_collection(&SINGLETON);
+    }
+
+    if !SINGLETON.get_plan().is_initialized() {
+        return;
+    }
+    if DEFERRED_GC.swap(false, Ordering::SeqCst) {
+        // Request the GC again. If MMTk has already started the GC, and it is waiting for the world
+        // to stop, the request joins that GC.
+        let reason = pending_gc_reason();
+        let exhaustive = reason == GCReason::UserFull;
+        request_gc(tls, reason, exhaustive);
+    } else {
+        // MMTk does not trigger a GC for a full heap while collection is disabled. Check it now.
+        memory_manager::gc_poll(&SINGLETON, tls);
+    }
+}
+
+/// The bytes allocated while collection was disabled, in total.
+pub fn deferred_alloc_bytes() -> usize {
+    DEFERRED_ALLOC_BYTES.load(Ordering::SeqCst)
+}
+
+/// Set up the triggers from the options. This is called before MMTk is initialized.
+/// We record the heap limits from the `gc_trigger` option, and leave the option as it is, so MMTk
+/// uses its own trigger. Only if the user sets `gc_trigger=Delegated`, MMTk asks `JuliaGCTrigger`
+/// for the heap size, which starts with the heap size given to mmtk_gc_init (`default_trigger`), and
+/// can be changed later. Without the `delegated_gc_trigger` feature, MMTk does not support a
+/// delegated trigger, and we use `default_trigger` instead.
+pub fn init(options: &mut Options, default_trigger: GCTriggerSelector) {
+    let malloc_threshold = crate::options::JULIA_OPTIONS
+        .read()
+        .unwrap()
+        .malloc_threshold;
+    MALLOC_THRESHOLD.store(malloc_threshold, Ordering::SeqCst);
+
+    let delegated = matches!(*options.gc_trigger, GCTriggerSelector::Delegated);
+    if delegated && !cfg!(feature = "delegated_gc_trigger") {
+        warn!(
+            "gc_trigger=Delegated requires the delegated_gc_trigger feature, using {:?}",
+            default_trigger
+        );
+        let success = options.gc_trigger.set(default_trigger);
+        assert!(
+            success,
+            "Failed to set the GC trigger to {:?}",
+            default_trigger
+        );
+    }
+
+    let trigger = if delegated {
+        default_trigger
+    } else {
+        *options.gc_trigger
+    };
+    let (min, max) = match trigger {
+        GCTriggerSelector::FixedHeapSize(size) => (0, size),
+        GCTriggerSelector::DynamicHeapSize(min, max) => (min, max),
+        GCTriggerSelector::Delegated => unreachable!(),
+    };
+    info!("Heap limits are {}-{} (in bytes)", min, max);
+    MIN_HEAP_SIZE.store(min, Ordering::SeqCst);
+    MAX_HEAP_SIZE.store(max, Ordering::SeqCst);
+    DELEGATED.store(
+        delegated && cfg!(feature = "delegated_gc_trigger"),
+        Ordering::SeqCst,
+    );
+}
+
+/// The physical memory of the machine, which is the largest heap we allow. Returns 0 if unknown.
+pub fn physical_memory() -> usize {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages <= 0 || page_size <= 0 {
+        return 0;
+    }
+    pages as usize * page_size as usize
+}
+
+pub fn get_heap_limits() -> (usize, usize) {
+    (
+        MIN_HEAP_SIZE.load(Ordering::SeqCst),
+        MAX_HEAP_SIZE.load(Ordering::SeqCst),
+    )
+}
+
+/// Set the heap limits. Returns false if the limits are invalid, or if they cannot be changed
+/// because MMTk uses its own trigger. The limits are in bytes.
+pub fn set_heap_limits(min: usize, max: usize) -> bool {
+    if !DELEGATED.load(Ordering::SeqCst) {
+        warn!("The heap limits can only be changed with gc_trigger=Delegated");
+        return false;
+    }
+    if max == 0 || min > max || max > usize::max(physical_memory(), get_heap_limits().1) {
+        return false;
+    }
+    info!("Setting heap limits to {}-{} (in bytes)", min, max);
+    MIN_HEAP_SIZE.store(min, Ordering::SeqCst);
+    MAX_HEAP_SIZE.store(max, Ordering::SeqCst);
+    true
+}
+
+/// Do not collect before GC threads are spawned. A GC while collection is disabled is deferred by
+/// `request_gc`.
+fn can_collect() -> bool {
+    SINGLETON.get_plan().is_initialized()
+}
+
+/// After a GC, a dynamic heap is sized to this many times the pages that are still reserved, within
+/// the heap limits.
+#[cfg(feature = "delegated_gc_trigger")]
+const HEAP_GROWTH_FACTOR: usize = 2;
+
+/// The GC trigger that MMTk uses for `GCTriggerSelector::Delegated`. It reads the heap limits from
+/// MIN_HEAP_SIZE and MAX_HEAP_SIZE every time, so the limits set by `mmtk_set_heap_limits` take
+/// effect at the next allocation. Otherwise, it triggers GCs like MMTk's own triggers: the plan
+/// decides whether a GC is required, and a GC is triggered when the reserved pages are over the
+/// current heap size.
+#[cfg(feature = "delegated_gc_trigger")]
+#[derive(Default)]
+pub struct JuliaGCTrigger {
+    /// The heap size of a dynamic heap, in pages, as computed after the last GC. This is clamped
+    /// to the current limits when it is read.
+    heap_pages: AtomicUsize,
+}
+
+#[cfg(feature = "delegated_gc_trigger")]
+impl JuliaGCTrigger {
+    fn limits_in_pages() -> (usize, usize) {
+        let (min, max) = get_heap_limits();
+        (
+            conversions::bytes_to_pages_up(min),
+            conversions::bytes_to_pages_up(max),
+        )
+    }
+}
+
+#[cfg(feature = "delegated_gc_trigger")]
+impl GCTriggerPolicy<JuliaVM> for JuliaGCTrigger {
+    fn on_gc_end(&self, mmtk: &'static MMTK<JuliaVM>) {
+        let reserved = mmtk.get_plan().get_reserved_pages();
+        self.heap_pages.store(
+            reserved.saturating_mul(HEAP_GROWTH_FACTOR),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn is_gc_required(
+        &self,
+        space_full: bool,
+        space: Option<SpaceStats<JuliaVM>>,
+        plan: &dyn Plan<VM = JuliaVM>,
+    ) -> bool {
+        plan.collection_required(space_full, space)
+    }
+
+    fn is_heap_full(&self, plan: &dyn Plan<VM = JuliaVM>) -> bool {
+        plan.get_reserved_pages() > self.get_current_heap_size_in_pages()
+    }
+
+    fn get_current_heap_size_in_pages(&self) -> usize {
+        let (min, max) = Self::limits_in_pages();
+        if min == 0 {
+            max
+        } else {
+            self.heap_pages.load(Ordering::Relaxed).clamp(min, max)
+        }
+    }
+
+    fn get_max_heap_size_in_pages(&self) -> usize {
+        Self::limits_in_pages().1
+    }
+
+    fn can_heap_size_grow(&self) -> bool {
+        self.get_current_heap_size_in_pages() < self.get_max_heap_size_in_pages()
+    }
+}
+
+/// Check how much the malloc'd memory has grown since the last GC, and trigger a collection if it
+/// has grown over the threshold. This is called by mutators when they malloc.
+pub fn poll_malloc(tls: VMMutatorThread) {
+    let threshold = MALLOC_THRESHOLD.load(Ordering::Relaxed);
+    if threshold == 0 {
+        return;
+    }
+    let malloc_bytes = VMCollection::vm_live_bytes();
+    let growth = malloc_bytes.saturating_sub(MALLOC_BYTES_AFTER_LAST_GC.load(Ordering::Relaxed));
+    if growth <= threshold || !can_collect() {
+        return;
+    }
+
+    info!(
+        "Malloc'd memory has grown by {} bytes since the last GC, triggering collection",
+        growth
+    );
+    request_gc(tls, GCReason::Malloc, false);
+}
+
+/// Record the malloc'd memory after a GC, as the baseline for the malloc trigger.
+pub fn reset_malloc_growth() {
+    MALLOC_BYTES_AFTER_LAST_GC.store(VMCollection::vm_live_bytes(), Ordering::Relaxed);
+}
@@ -1,55 +1,311 @@
-use crate::UPCALLS;
+use crate::reference_glue::JuliaFinalizableObject;
+use crate::{SINGLETON, UPCALLS};
 use mmtk::memory_manager;
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
 use mmtk::util::Address;
 use mmtk::util::ObjectReference;
-use mmtk::vm::ObjectTracer;
-use mmtk::vm::VMBinding;
+use mmtk::vm::{ObjectTracer, ObjectTracerContext};
 use mmtk::Mutator;
+use mmtk::MMTK;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crate::JuliaVM;
 
-/// This is a Rust implementation of finalizer scanning in _jl_gc_collect() in gc.c
-pub fn scan_finalizers_in_rust<T: ObjectTracer>(tracer: &mut T) {
-    use crate::mmtk::vm::ActivePlan;
-    let to_finalize = ArrayListT::to_finalize_list();
-    let marked_finalizers_list = ArrayListT::marked_finalizers_list();
-    let jl_gc_have_pending_finalizers: *mut i32 =
-        unsafe { ((*UPCALLS).get_jl_gc_have_pending_finalizers)() };
-
-    // Current length of marked list: we only need to trace objects after this length if this is a nursery GC.
-    let mut orig_marked_len = marked_finalizers_list.len;
-
-    // Sweep thread local list: if they are not alive, move to to_finalize.
-    for mutator in <JuliaVM as VMBinding>::VMActivePlan::mutators() {
-        let list = ArrayListT::thread_local_finalizer_list(mutator);
-        sweep_finalizer_list(
-            list,
-            to_finalize,
-            Some(marked_finalizers_list),
-            jl_gc_have_pending_finalizers,
+/// The number of entries in the marked list or to_finalize that one work packet sweeps or traces.
+/// It is even, so a chunk does not separate an object from its finalizer.
+const FINALIZER_CHUNK_SIZE: usize = 4096;
+
+/// This is a parallel version of finalizer scanning in _jl_gc_collect() in gc.c. The scan has two
+/// phases, each with a number of work packets in the `VMRefClosure` bucket:
+/// * Sweep: each thread local list, the orphan list and, in a full heap GC, each chunk of the
+///   marked list is swept in its own packet. The packets collect the entries to move in
+///   `FinalizerScan`, rather than pushing to the shared lists at the same time.
+/// * Mark: the last sweep packet merges the entries into to_finalize and the marked list, and adds
+///   packets that trace the objects in chunks of the lists. We cannot trace anything before all the
+///   lists are swept, as tracing makes the objects live.
+pub fn schedule_finalizer_scan<C: ObjectTracerContext<JuliaVM>>(tracer_context: C) {
+    let is_nursery = crate::collection::is_current_gc_nursery();
+    let marked_len = ArrayListT::marked_finalizers_list().len;
+
+    // The mutators are stopped, so their lists do not change while we sweep them.
+    let mut works: Vec<FinalizerWork> = crate::active_plan::mutators_snapshot()
+        .into_iter()
+        .map(|mutator| {
+            let list = ArrayListT::thread_local_finalizer_list(mutator);
+            FinalizerWork::SweepThreadList(Address::from_mut_ptr(list as *mut ArrayListT))
+        })
+        .collect();
+    works.push(FinalizerWork::SweepOrphans);
+    // If this is a full heap GC, we also sweep marked list.
+    if !is_nursery {
+        works.extend(
+            chunks(0, marked_len).map(|(start, end)| FinalizerWork::SweepMarked(start, end)),
         );
     }
 
-    // If this is a full heap GC, we also sweep marked list.
-    if !crate::collection::is_current_gc_nursery() {
-        sweep_finalizer_list(
-            marked_finalizers_list,
-            to_finalize,
-            None,
-            jl_gc_have_pending_finalizers,
+    let scan = Arc::new(FinalizerScan {
+        pending_sweeps: AtomicUsize::new(works.len()),
+        is_nursery,
+        // We only need to trace objects after this length if this is a nursery GC.
+        orig_marked_len: if is_nursery { marked_len } else { 0 },
+        to_finalize: Mutex::new(vec![]),
+        marked: Mutex::new(vec![]),
+    });
+    add_finalizer_work(&scan, &tracer_context, works);
+}
+
+/// The state of the finalizer scan of a GC, shared by its work packets.
+struct FinalizerScan {
+    /// The number of sweep packets that have not finished.
+    pending_sweeps: AtomicUsize,
+    is_nursery: bool,
+    /// The length of the marked list that is already traced.
+    orig_marked_len: usize,
+    /// The entries to push to to_finalize and the marked list.
+    to_finalize: Mutex<Vec<Address>>,
+    marked: Mutex<Vec<Address>>,
+}
+
+impl FinalizerScan {
+    /// Called by each sweep packet when it is done. Returns the work of the mark phase if this is the
+    /// last sweep packet.
+    fn sweep_done(&self) -> Option<Vec<FinalizerWork>> {
+        if self.pending_sweeps.fetch_sub(1, Ordering::SeqCst) != 1 {
+            return None;
+        }
+
+        let to_finalize = ArrayListT::to_finalize_list();
+        let marked_finalizers_list = ArrayListT::marked_finalizers_list();
+        if !self.is_nursery {
+            remove_cleared_entries(marked_finalizers_list);
+        }
+        for entry in self.marked.lock().unwrap().drain(..) {
+            marked_finalizers_list.push(entry);
+        }
+        let mut new_to_finalize = self.to_finalize.lock().unwrap();
+        if !new_to_finalize.is_empty() {
+            unsafe { *((*UPCALLS).get_jl_gc_have_pending_finalizers)() = 1 };
+        }
+        for entry in new_to_finalize.drain(..) {
+            to_finalize.push(entry);
+        }
+
+        // Trace new objects in marked list, and objects in to_finalize. The sweep moves all the
+        // entries out of the thread local lists and the orphan list, so there is nothing to trace
+        // in them.
+        let mut works: Vec<FinalizerWork> =
+            chunks(self.orig_marked_len, marked_finalizers_list.len)
+                .map(|(start, end)| FinalizerWork::MarkMarked(start, end))
+                .collect();
+        works.extend(
+            chunks(0, to_finalize.len)
+                .map(|(start, end)| FinalizerWork::MarkToFinalize(start, end)),
         );
-        orig_marked_len = 0;
+        if !READY_FINALIZERS.lock().unwrap().is_empty() {
+            works.push(FinalizerWork::MarkReady);
+        }
+        Some(works)
     }
 
-    // Go through thread local list again and trace objects
-    for mutator in <JuliaVM as VMBinding>::VMActivePlan::mutators() {
-        let list = ArrayListT::thread_local_finalizer_list(mutator);
-        mark_finlist(list, 0, tracer);
+    fn add_swept(&self, to_finalize: Vec<Address>, marked: Vec<Address>) {
+        if !to_finalize.is_empty() {
+            self.to_finalize.lock().unwrap().extend(to_finalize);
+        }
+        if !marked.is_empty() {
+            self.marked.lock().unwrap().extend(marked);
+        }
+    }
+}
+
+enum FinalizerWork {
+    /// Sweep a thread local list, at the address.
+    SweepThreadList(Address),
+    /// Sweep the orphan list.
+    SweepOrphans,
+    /// Sweep a range of the marked list.
+    SweepMarked(usize, usize),
+    /// Trace the objects in a range of the marked list.
+    MarkMarked(usize, usize),
+    /// Trace the objects in a range of to_finalize.
+    MarkToFinalize(usize, usize),
+    /// Trace the objects in `READY_FINALIZERS`.
+    MarkReady,
+}
+
+/// A work packet of the finalizer scan.
+struct ScanFinalizers<C: ObjectTracerContext<JuliaVM>> {
+    scan: Arc<FinalizerScan>,
+    work: FinalizerWork,
+    tracer_context: C,
+}
+
+impl<C: ObjectTracerContext<JuliaVM>> GCWork<JuliaVM> for ScanFinalizers<C> {
+    fn do_work(&mut self, worker: &mut GCWorker<JuliaVM>, _mmtk: &'static MMTK<JuliaVM>) {
+        crate::gc_stats::FINALIZERS.time(|| {
+            let (mut to_finalize, mut marked) = (vec![], vec![]);
+            match self.work {
+                FinalizerWork::SweepThreadList(list) => {
+                    let list = unsafe { &mut *list.to_mut_ptr::<ArrayListT>() };
+                    sweep_finalizer_list(list, &mut to_finalize, &mut marked);
+                }
+                FinalizerWork::SweepOrphans => {
+                    // The finalizers of threads that have exited are swept like a thread local list.
+                    // The thread that runs the GC holds finalizers_lock, so no mutator is destroyed
+                    // while we sweep.
+                    let list = ArrayListT::orphan_finalizers_list();
+                    sweep_finalizer_list(list, &mut to_finalize, &mut marked);
+                }
+                FinalizerWork::SweepMarked(start, end) => {
+                    let list = ArrayListT::marked_finalizers_list();
+                    sweep_marked_chunk(list, start, end, &mut to_finalize);
+                }
+                FinalizerWork::MarkMarked(start, end) => {
+                    let list = ArrayListT::marked_finalizers_list();
+                    self.tracer_context
+                        .with_tracer(worker, |tracer| mark_finlist(list, start, end, tracer));
+                    return;
+                }
+                FinalizerWork::MarkToFinalize(start, end) => {
+                    let list = ArrayListT::to_finalize_list();
+                    self.tracer_context
+                        .with_tracer(worker, |tracer| mark_finlist(list, start, end, tracer));
+                    return;
+                }
+                FinalizerWork::MarkReady => {
+                    let mut ready = READY_FINALIZERS.lock().unwrap();
+                    self.tracer_context
+                        .with_tracer(worker, |tracer| mark_ready_finalizers(&mut ready, tracer));
+                    return;
+                }
+            }
+
+            self.scan.add_swept(to_finalize, marked);
+            if let Some(works) = self.scan.sweep_done() {
+                add_finalizer_work(&self.scan, &self.tracer_context, works);
+            }
+        });
     }
-    // Trace new objects in marked list
-    mark_finlist(marked_finalizers_list, orig_marked_len, tracer);
-    // Trace objects in to_finalize (which are just pushed in sweeping thread local list)
-    mark_finlist(to_finalize, 0, tracer);
+}
+
+fn add_finalizer_work<C: ObjectTracerContext<JuliaVM>>(
+    scan: &Arc<FinalizerScan>,
+    tracer_context: &C,
+    works: Vec<FinalizerWork>,
+) {
+    let packets = works
+        .into_iter()
+        .map(|work| {
+            Box::new(ScanFinalizers {
+                scan: scan.clone(),
+                work,
+                tracer_context: tracer_context.clone(),
+            }) as Box<dyn GCWork<JuliaVM>>
+        })
+        .collect();
+    memory_manager::add_work_packets(&SINGLETON, WorkBucketStage::VMRefClosure, packets);
+}
+
+/// Split a range of a list into chunks of `FINALIZER_CHUNK_SIZE`.
+fn chunks(start: usize, end: usize) -> impl Iterator<Item = (usize, usize)> {
+    (start..end)
+        .step_by(FINALIZER_CHUNK_SIZE)
+        .map(move |chunk| (chunk, usize::min(chunk + FINALIZER_CHUNK_SIZE, end)))
+}
+
+// Whether MMTk has found finalizable objects that are dead in this GC.
+static FINALIZERS_READY: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // The objects that MMTk found dead (with `julia_native_finalizers`), whose finalizers have not
+    // run yet.
+    static ref READY_FINALIZERS: Mutex<Vec<JuliaFinalizableObject>> = Mutex::new(vec![]);
+}
+
+/// Whether finalizers are registered with MMTk's finalizer processor (`julia_native_finalizers`)
+/// rather than in the thread local lists.
+pub fn native_finalizers_enabled() -> bool {
+    crate::options::JULIA_OPTIONS
+        .read()
+        .unwrap()
+        .native_finalizers
+}
+
+/// Called by MMTk after it scans the objects registered with `mmtk_register_finalizer`. MMTk holds
+/// the lock of its finalizer processor here, so we cannot get the objects yet. We queue them in
+/// `queue_ready_finalizers` when the GC is done.
+pub fn schedule_finalization() {
+    FINALIZERS_READY.store(true, Ordering::SeqCst);
+}
+
+/// Move the objects that MMTk found dead to `READY_FINALIZERS`. Their finalizers run from there
+/// through `run_finalizer_function` (see `run_ready_finalizers`), rather than from Julia's
+/// to_finalize. MMTk only keeps the objects alive while they are in its own queue, so
+/// `READY_FINALIZERS` is traced in every GC until the finalizers run, like to_finalize. This is
+/// called before the mutators resume.
+pub fn queue_ready_finalizers() {
+    if !FINALIZERS_READY.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut ready = READY_FINALIZERS.lock().unwrap();
+    while let Some(finalizable) = memory_manager::get_finalized_object(&SINGLETON) {
+        ready.push(finalizable);
+    }
+}
+
+/// Run the finalizers in `READY_FINALIZERS` on the current thread. A finalizer may trigger a GC, so
+/// we do not hold the lock while it runs. `run_finalizer_function` roots the object once it is out
+/// of the queue.
+pub fn run_ready_finalizers() {
+    loop {
+        let finalizable = READY_FINALIZERS.lock().unwrap().pop();
+        match finalizable {
+            Some(finalizable) => finalizable.run(),
+            None => break,
+        }
+    }
+}
+
+fn mark_ready_finalizers(ready: &mut [JuliaFinalizableObject], tracer: &mut impl ObjectTracer) {
+    for finalizable in ready.iter_mut() {
+        finalizable.0 = tracer.trace_object(finalizable.0);
+        // The finalizer is a Julia function unless it is a C function pointer.
+        if !finalizable.2 {
+            finalizable.1 = tracer
+                .trace_object(ObjectReference::from_raw_address(finalizable.1))
+                .to_raw_address();
+        }
+    }
+}
+
+/// Move the finalizers left in the thread local list of a mutator that is being destroyed to the
+/// orphan list. Otherwise they would not be swept or traced once the mutator is gone, and would
+/// never run. The next GC sweeps the orphan list like a thread local list. We hold Julia's
+/// finalizers_lock, like the other code that changes the finalizer lists.
+pub fn orphan_thread_finalizers(mutator: &Mutator<JuliaVM>) {
+    unsafe { ((*UPCALLS).lock_finalizers)() };
+    let list = ArrayListT::thread_local_finalizer_list(mutator);
+    let orphan_finalizers = ArrayListT::orphan_finalizers_list();
+    let mut i = 0;
+    while i < list.len {
+        // Skip the entries that were removed.
+        if !list.get(i).is_zero() {
+            orphan_finalizers.push(list.get(i));
+            orphan_finalizers.push(list.get(i + 1));
+        }
+        i += 2;
+    }
+    list.len = 0;
+    unsafe { ((*UPCALLS).unlock_finalizers)() };
+}
+
+/// The number of finalizers that are waiting to run. Each entry in to_finalize is a pair of an
+/// object and its finalizer.
+pub fn num_queued_finalizers() -> usize {
+    // The finalizer thread may be draining the list at the same time.
+    let list = ArrayListT::to_finalize_list();
+    unsafe { std::ptr::read_volatile(&list.len) / 2 + READY_FINALIZERS.lock().unwrap().len() }
 }
 
 /// This maps to arraylist_t in arraylist.h. Defining the type allows us to access the list in Rust.
@@ -80,6 +336,11 @@ impl ArrayListT {
         let list = unsafe { ((*UPCALLS).get_to_finalize_list)() };
         unsafe { &mut *list.to_mut_ptr() }
     }
+    /// orphan_finalizers: finalizers of threads that have exited, see `orphan_thread_finalizers`.
+    fn orphan_finalizers_list<'a>() -> &'a mut ArrayListT {
+        let list = unsafe { ((*UPCALLS).get_orphan_finalizers_list)() };
+        unsafe { &mut *list.to_mut_ptr() }
+    }
     /// finalizer_list_marked: objects that are alive and traced, thus we do not need to scan them again in future nursery GCs.
     fn marked_finalizers_list<'a>() -> &'a mut ArrayListT {
         let list = unsafe { ((*UPCALLS).get_marked_finalizers_list)() };
@@ -94,6 +355,17 @@ impl ArrayListT {
         debug_assert!(i < self.len);
         unsafe { *self.items.add(i) = val }
     }
+    /// Update an item. This only needs a shared reference, so packets can update items in different
+    /// parts of a list at the same time.
+    fn update(&self, i: usize, val: Address) {
+        debug_assert!(i < self.len);
+        unsafe { *self.items.add(i) = val }
+    }
+    /// Clear an object and its finalizer.
+    fn clear_entry(&self, i: usize) {
+        self.update(i, Address::ZERO);
+        self.update(i + 1, Address::ZERO);
+    }
     fn push(&mut self, val: Address) {
         self.grow(1);
         self.set(self.len - 1, val);
@@ -118,79 +390,99 @@ fn gc_ptr_tag(addr: Address, tag: usize) -> bool {
     addr & tag != 0
 }
 
-// sweep_finalizer_list in gc.c
+// Replace the object in a list item with another object, and keep the tag bits of the item.
+fn gc_ptr_set_object(addr: Address, object: ObjectReference) -> Address {
+    unsafe { Address::from_usize(object.to_raw_address().as_usize() | (addr & 3usize)) }
+}
+
+// If the object of a list item has been moved, update the item to the new object.
+fn forward_finalizer_entry(v0: Address) -> Address {
+    match gc_ptr_clear_tag(v0, 3).get_forwarded_object() {
+        Some(new_obj) => gc_ptr_set_object(v0, new_obj),
+        None => v0,
+    }
+}
+
+// Whether the object of a finalizer entry is dead, so the finalizer should run.
+fn is_finalizer_freed(v0: Address) -> bool {
+    gc_ptr_tag(v0, 2) || !memory_manager::is_live_object(gc_ptr_clear_tag(v0, 3))
+}
+
+// sweep_finalizer_list in gc.c, for a thread local list. The entries of dead objects are moved to
+// to_finalize, and the entries of live objects are moved to the marked list, so the list is empty
+// after sweeping.
 fn sweep_finalizer_list(
     list: &mut ArrayListT,
-    to_finalize: &mut ArrayListT,
-    // finalizer_list_marked is None if list (1st parameter) is finalizer_list_marked.
-    // Rust does not allow sending the same mutable reference as two different arguments (cannot borrow __ as mutable more than once at a time)
-    mut finalizer_list_marked: Option<&mut ArrayListT>,
-    jl_gc_have_pending_finalizers: *mut i32,
+    to_finalize: &mut Vec<Address>,
+    finalizer_list_marked: &mut Vec<Address>,
 ) {
-    if list.len == 0 {
-        return;
-    }
-
     let mut i = 0;
-    let mut j = 0;
     while i < list.len {
         let v0: Address = list.get(i);
-        let v = gc_ptr_clear_tag(v0, 3);
-        if v0.is_zero() {
-            i += 2;
-            // remove from this list
-            continue;
+        // Skip the entries that were removed.
+        if !v0.is_zero() {
+            let fin = list.get(i + 1);
+            if is_finalizer_freed(v0) {
+                to_finalize.push(v0);
+                to_finalize.push(fin);
+            } else {
+                finalizer_list_marked.push(forward_finalizer_entry(v0));
+                finalizer_list_marked.push(fin);
+            }
         }
+        i += 2;
+    }
 
-        let fin = list.get(i + 1);
-        let (isfreed, isold) = if gc_ptr_tag(v0, 2) {
-            (true, false)
-        } else {
-            let isfreed = !memory_manager::is_live_object(v);
-            let isold = finalizer_list_marked.is_some() && !isfreed;
-            (isfreed, isold)
-        };
-        if isfreed || isold {
-            // remove from this list
+    list.len = 0;
+}
+
+// sweep_finalizer_list in gc.c, for a range of the marked list. The entries of dead objects are
+// moved to to_finalize. Other packets sweep the rest of the list at the same time, so we only clear
+// the entries here, and remove them with `remove_cleared_entries` when all the sweeping is done.
+fn sweep_marked_chunk(list: &ArrayListT, start: usize, end: usize, to_finalize: &mut Vec<Address>) {
+    let mut i = start;
+    while i < end {
+        let v0: Address = list.get(i);
+        if v0.is_zero() {
+            // Removed already
+        } else if is_finalizer_freed(v0) {
+            to_finalize.push(v0);
+            to_finalize.push(list.get(i + 1));
+            list.clear_entry(i);
         } else {
+            list.update(i, forward_finalizer_entry(v0));
+        }
+        i += 2;
+    }
+}
+
+fn remove_cleared_entries(list: &mut ArrayListT) {
+    let mut i = 0;
+    let mut j = 0;
+    while i < list.len {
+        if !list.get(i).is_zero() {
             if j < i {
                 list.set(j, list.get(i));
                 list.set(j + 1, list.get(i + 1));
             }
             j += 2;
         }
-        if isfreed {
-            to_finalize.push(v0);
-            to_finalize.push(fin);
-            unsafe {
-                *jl_gc_have_pending_finalizers = 1;
-            }
-        }
-        if isold {
-            let finalizer_list_marked = finalizer_list_marked.as_mut().unwrap();
-            finalizer_list_marked.push(v0);
-            finalizer_list_marked.push(fin);
-        }
         i += 2;
     }
-
     list.len = j;
 }
 
 // gc_mark_finlist in gc.c
-fn mark_finlist<T: ObjectTracer>(list: &mut ArrayListT, start: usize, tracer: &mut T) {
-    if list.len <= start {
-        return;
-    }
-
+fn mark_finlist<T: ObjectTracer>(list: &ArrayListT, start: usize, end: usize, tracer: &mut T) {
     let mut i = start;
-    while i < list.len {
+    while i < end {
         let cur = list.get(i);
         if cur.is_zero() {
             i += 1;
             continue;
         }
 
+        let obj_index = i;
         let new_obj = if gc_ptr_tag(cur, 1) {
             // Skip next
             i += 1;
@@ -204,11 +496,11 @@ fn mark_finlist<T: ObjectTracer>(list: &mut ArrayListT, start: usize, tracer: &m
             continue;
         }
 
+        // The object may be moved. Save the new object back to the finalizer list, with the tag.
         let traced = tracer.trace_object(new_obj);
-        debug_assert_eq!(
-            traced, new_obj,
-            "Object is moved -- we need to save the new object back to the finalizer list"
-        );
+        if traced != new_obj {
+            list.update(obj_index, gc_ptr_set_object(cur, traced));
+        }
         i += 1;
     }
 }
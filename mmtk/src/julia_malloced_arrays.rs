@@ -0,0 +1,126 @@
+use crate::julia_types::*;
+use crate::{JuliaVM, SINGLETON, UPCALLS};
+use mmtk::memory_manager;
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::{Address, ObjectReference};
+use mmtk::Mutator;
+use mmtk::MMTK;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    // The head of each mutator's list (by the list address) after the last sweep. The arrays from
+    // this entry on have survived a GC. In a generational plan, they are old, and a nursery GC does
+    // not need to sweep them.
+    static ref OLD_MALLOCED_ARRAYS: Mutex<HashMap<Address, Address>> = Mutex::new(HashMap::new());
+}
+
+/// This is a parallel version of sweep_malloced_arrays() in gc.c. Each mutator's list of malloc'd
+/// arrays is swept in its own packet, and the system image is swept in another packet. The mutators
+/// are stopped, so their lists do not change while we sweep them. With `julia_los_array_buffers`,
+/// the buffers that Julia allocates with `jl_mmtk_alloc_array_buffer` are in MMTk's large object
+/// space, but Julia still mallocs the buffers of the arrays it grows or wraps, so we sweep in both
+/// modes.
+pub fn schedule_malloced_arrays_sweep() {
+    let is_nursery = crate::collection::is_current_gc_nursery();
+    let mut works: Vec<Box<dyn GCWork<JuliaVM>>> = crate::active_plan::mutators_snapshot()
+        .into_iter()
+        .map(|mutator| {
+            Box::new(SweepMallocedArrays {
+                heap: thread_heap(mutator),
+                is_nursery,
+            }) as Box<dyn GCWork<JuliaVM>>
+        })
+        .collect();
+    works.push(Box::new(SweepSysimg));
+    memory_manager::add_work_packets(&SINGLETON, WorkBucketStage::Compact, works);
+}
+
+/// Called when a mutator is destroyed. Its list is not swept any more, so we forget its old
+/// entries, as the memory of the list may be reused.
+pub fn forget_mutator(mutator: &Mutator<JuliaVM>) {
+    let list = Address::from_mut_ptr(unsafe { &mut (*thread_heap(mutator)).mallocarrays });
+    OLD_MALLOCED_ARRAYS.lock().unwrap().remove(&list);
+}
+
+fn thread_heap(mutator: &Mutator<JuliaVM>) -> *mut mmtk_jl_thread_heap_t {
+    let ptls = mutator.mutator_tls.0 .0.to_address();
+    unsafe { &mut (*ptls.to_mut_ptr::<mmtk_jl_tls_states_t>()).heap }
+}
+
+/// Sweep the malloc'd arrays of a mutator: free the arrays that are dead, and move their entries to
+/// the free list.
+struct SweepMallocedArrays {
+    heap: *mut mmtk_jl_thread_heap_t,
+    is_nursery: bool,
+}
+
+unsafe impl Send for SweepMallocedArrays {}
+
+impl GCWork<JuliaVM> for SweepMallocedArrays {
+    fn do_work(&mut self, _worker: &mut GCWorker<JuliaVM>, _mmtk: &'static MMTK<JuliaVM>) {
+        crate::gc_stats::SWEEP_MALLOCED_ARRAYS.time(|| unsafe { self.sweep() });
+    }
+}
+
+impl SweepMallocedArrays {
+    unsafe fn sweep(&self) {
+        let heap = &mut *self.heap;
+        let list = Address::from_mut_ptr(&mut heap.mallocarrays);
+        // In a nursery GC, stop at the first old entry.
+        let old = if self.is_nursery {
+            OLD_MALLOCED_ARRAYS.lock().unwrap().get(&list).copied()
+        } else {
+            None
+        }
+        .unwrap_or(Address::ZERO);
+
+        let mut ma = heap.mallocarrays;
+        let mut pma: *mut *mut mmtk_mallocarray_t = &mut heap.mallocarrays;
+        while !ma.is_null() && Address::from_mut_ptr(ma) != old {
+            let nxt = (*ma).next;
+            let object = ObjectReference::from_raw_address(Address::from_mut_ptr((*ma).a));
+            if !memory_manager::is_in_mmtk_spaces::<JuliaVM>(object)
+                || memory_manager::is_live_object(object)
+            {
+                if let Some(forwarded) = object.get_forwarded_object() {
+                    (*ma).a = forwarded.to_raw_address().to_mut_ptr();
+                }
+                pma = &mut (*ma).next;
+            } else {
+                *pma = nxt;
+                debug_assert_eq!((*(*ma).a).flags.how_custom(), 2);
+                // A buffer in MMTk's large object space is reclaimed by MMTk with the array.
+                // free_array can be called by several packets at once.
+                if !is_los_buffer(&*(*ma).a) {
+                    ((*UPCALLS).free_array)(Address::from_mut_ptr((*ma).a));
+                }
+                (*ma).next = heap.mafreelist;
+                heap.mafreelist = ma;
+            }
+            ma = nxt;
+        }
+
+        OLD_MALLOCED_ARRAYS
+            .lock()
+            .unwrap()
+            .insert(list, Address::from_mut_ptr(heap.mallocarrays));
+    }
+}
+
+/// Whether the buffer of the array was allocated by `jl_mmtk_alloc_array_buffer` rather than with
+/// malloc. This can only be true with `julia_los_array_buffers`.
+unsafe fn is_los_buffer(array: &mmtk_jl_array_t) -> bool {
+    let offset = array.offset as usize * array.elsize as usize;
+    let buffer = Address::from_mut_ptr(array.data) - offset;
+    memory_manager::is_in_mmtk_spaces::<JuliaVM>(ObjectReference::from_raw_address(buffer))
+}
+
+/// Sweep the system image, which is done in sweep_malloced_arrays() in gc.c as well.
+struct SweepSysimg;
+
+impl GCWork<JuliaVM> for SweepSysimg {
+    fn do_work(&mut self, _worker: &mut GCWorker<JuliaVM>, _mmtk: &'static MMTK<JuliaVM>) {
+        crate::gc_stats::SWEEP_MALLOCED_ARRAYS.time(|| unsafe { ((*UPCALLS).sweep_sysimg)() });
+    }
+}
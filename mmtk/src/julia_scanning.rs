@@ -178,6 +178,18 @@ pub unsafe fn scan_julia_object<EV: EdgeVisitor<JuliaVMEdge>>(obj: Address, clos
         return;
     }
 
+    // The key and the value of an ephemeron are processed after the transitive closure.
+    if crate::weak_refs::is_ephemeron_type(vt) {
+        crate::weak_refs::discover_ephemeron(obj);
+        return;
+    }
+
+    // The value of a soft reference is processed after the transitive closure.
+    if crate::weak_refs::is_soft_ref_type(vt) {
+        crate::weak_refs::discover_soft_ref(obj);
+        return;
+    }
+
     // scan_julia_object_fallback(obj, closure);
     // return;
 
@@ -436,7 +448,7 @@ pub unsafe fn scan_julia_object<EV: EdgeVisitor<JuliaVMEdge>>(obj: Address, clos
         }
 
         if vt == jl_weakref_type {
-            // println!("obj:{}/WeakRef*", obj);
+            crate::weak_refs::discover_weak_ref(obj);
             return;
         }
 
@@ -546,6 +558,18 @@ pub unsafe fn scan_julia_object_fallback<EV: EdgeVisitor<JuliaVMEdge>>(obj: Addr
         return;
     }
 
+    // The key and the value of an ephemeron are processed after the transitive closure.
+    if crate::weak_refs::is_ephemeron_type(vt) {
+        crate::weak_refs::discover_ephemeron(obj);
+        return;
+    }
+
+    // The value of a soft reference is processed after the transitive closure.
+    if crate::weak_refs::is_soft_ref_type(vt) {
+        crate::weak_refs::discover_soft_ref(obj);
+        return;
+    }
+
     if vt == jl_simplevector_type {
         if PRINT_OBJ_TYPE {
             println!("scan_julia_obj {}: simple vector\n", obj);
@@ -721,6 +745,7 @@ pub unsafe fn scan_julia_object_fallback<EV: EdgeVisitor<JuliaVMEdge>>(obj: Addr
         }
 
         if vt == jl_weakref_type {
+            crate::weak_refs::discover_weak_ref(obj);
             return;
         }
 
@@ -1070,6 +1095,15 @@ pub unsafe fn is_val_array(obj: Address) -> bool {
 }
 
 pub unsafe fn get_obj_category(obj: Address) -> i32 {
+    // Ephemerons, weak references and soft references are scanned by the binding, so their fields
+    // are not traced as strong edges.
+    let vt = mmtk_jl_typeof(obj);
+    if crate::weak_refs::is_ephemeron_type(vt)
+        || vt == jl_weakref_type
+        || crate::weak_refs::is_soft_ref_type(vt)
+    {
+        return 0;
+    }
     let category = mmtk_jl_get_category(obj);
     match category {
         JuliaObjectKind::SimpleVector | JuliaObjectKind::Array 
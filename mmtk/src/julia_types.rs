@@ -5745,11 +5745,53 @@ fn bindgen_test_layout_mmtk_jl_thread_gc_num_t() {
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
+pub struct mmtk__mallocarray_t {
+    pub a: *mut mmtk_jl_array_t,
+    pub next: *mut mmtk__mallocarray_t,
+}
+#[test]
+fn bindgen_test_layout_mmtk__mallocarray_t() {
+    const UNINIT: ::std::mem::MaybeUninit<mmtk__mallocarray_t> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<mmtk__mallocarray_t>(),
+        16usize,
+        concat!("Size of: ", stringify!(mmtk__mallocarray_t))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<mmtk__mallocarray_t>(),
+        8usize,
+        concat!("Alignment of ", stringify!(mmtk__mallocarray_t))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).a) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mmtk__mallocarray_t),
+            "::",
+            stringify!(a)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).next) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(mmtk__mallocarray_t),
+            "::",
+            stringify!(next)
+        )
+    );
+}
+pub type mmtk_mallocarray_t = mmtk__mallocarray_t;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
 pub struct mmtk_jl_thread_heap_t {
     pub weak_refs: mmtk_arraylist_t,
     pub live_tasks: mmtk_arraylist_t,
-    pub mallocarrays: *mut _mallocarray_t,
-    pub mafreelist: *mut _mallocarray_t,
+    pub mallocarrays: *mut mmtk__mallocarray_t,
+    pub mafreelist: *mut mmtk__mallocarray_t,
     pub big_objects: *mut _bigval_t,
     pub _remset: [mmtk_arraylist_t; 2usize],
     pub remset_nptr: ::std::os::raw::c_int,
@@ -7441,11 +7483,6 @@ pub struct mmtk__jl_binding_t {
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
-pub struct _mallocarray_t {
-    pub _address: u8,
-}
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
 pub struct _bigval_t {
     pub _address: u8,
 }
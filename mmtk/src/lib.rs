@@ -10,22 +10,31 @@ use mmtk::vm::VMBinding;
 use mmtk::MMTKBuilder;
 use mmtk::MMTK;
 
-use std::collections::HashMap;
 use std::ptr::null_mut;
-use std::sync::atomic::AtomicIsize;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, RwLock};
 
 pub mod active_plan;
 pub mod api;
+pub mod cgroup;
 pub mod collection;
 pub mod edges;
+pub mod finalizer_thread;
+pub mod gc_callbacks;
+pub mod gc_log;
+pub mod gc_stats;
+pub mod gc_trigger;
+pub mod malloc;
 pub mod object_model;
+pub mod oom;
+pub mod options;
 pub mod reference_glue;
 pub mod scanning;
 pub mod util;
+pub mod weak_refs;
 
 pub mod julia_finalizer;
+pub mod julia_malloced_arrays;
 pub mod julia_scanning;
 #[allow(non_camel_case_types)]
 #[allow(improper_ctypes_definitions)]
@@ -36,6 +45,7 @@ pub mod julia_types;
 #[derive(Default)]
 pub struct JuliaVM;
 
+use crate::active_plan::MutatorRegistry;
 use crate::edges::JuliaVMEdge;
 
 impl VMBinding for JuliaVM {
@@ -73,23 +83,14 @@ pub static BLOCK_FOR_GC: AtomicBool = AtomicBool::new(false);
 #[no_mangle]
 pub static WORLD_HAS_STOPPED: AtomicBool = AtomicBool::new(false);
 
-#[no_mangle]
-pub static DISABLED_GC: AtomicBool = AtomicBool::new(false);
-
-#[no_mangle]
-pub static USER_TRIGGERED_GC: AtomicIsize = AtomicIsize::new(0);
-
 lazy_static! {
     pub static ref STW_COND: Arc<(Mutex<usize>, Condvar)> =
         Arc::new((Mutex::new(0), Condvar::new()));
     pub static ref STOP_MUTATORS: Arc<(Mutex<usize>, Condvar)> =
         Arc::new((Mutex::new(0), Condvar::new()));
 
-    // We create a boxed mutator with MMTk core, and we mem copy its content to jl_tls_state_t (shallow copy).
-    // This map stores the pair of the mutator address in jl_tls_state_t and the original boxed mutator.
-    // As we only do a shallow copy, we should not free the original boxed mutator, until the thread is getting destroyed.
-    // Otherwise, we will have dangling pointers.
-    pub static ref MUTATORS: RwLock<HashMap<Address, Address>> = RwLock::new(HashMap::new());
+    // The mutators that are bound, see MutatorRegistry.
+    pub static ref MUTATORS: RwLock<MutatorRegistry> = RwLock::new(MutatorRegistry::default());
 }
 
 type ProcessEdgeFn = *const extern "C" fn(closure: Address, slot: Address);
@@ -101,7 +102,7 @@ pub struct Julia_Upcalls {
     pub get_stackbase: extern "C" fn(tid: u16) -> usize,
     pub mmtk_jl_run_finalizers: extern "C" fn(tls: OpaquePointer),
     pub jl_throw_out_of_memory_error: extern "C" fn(),
-    pub mmtk_sweep_malloced_array: extern "C" fn(),
+    pub sweep_sysimg: extern "C" fn(),
     pub wait_in_a_safepoint: extern "C" fn(),
     pub exit_from_safepoint: extern "C" fn(old_state: i8),
     pub jl_hrtime: extern "C" fn() -> u64,
@@ -113,7 +114,14 @@ pub struct Julia_Upcalls {
     pub arraylist_grow: extern "C" fn(Address, usize),
     pub get_jl_gc_have_pending_finalizers: extern "C" fn() -> *mut i32,
     pub scan_vm_specific_roots: extern "C" fn(closure: *mut crate::edges::RootsWorkClosure),
-    pub prepare_to_collect: extern "C" fn(),
+    pub prepare_to_collect: extern "C" fn(reason: crate::gc_trigger::GCReason) -> bool,
+    pub lock_finalizers: extern "C" fn(),
+    pub unlock_finalizers: extern "C" fn(),
+    pub get_orphan_finalizers_list: extern "C" fn() -> Address,
+    pub run_finalizer_function: extern "C" fn(obj: Address, function: Address, is_ptr: bool),
+    pub run_finalizer_thread: extern "C" fn(),
+    pub get_current_ptls: extern "C" fn() -> OpaquePointer,
+    pub free_array: extern "C" fn(a: Address),
 }
 
 pub static mut UPCALLS: *const Julia_Upcalls = null_mut();
@@ -0,0 +1,108 @@
+use crate::api::JULIA_MALLOC_BYTES;
+use libc::c_void;
+use mmtk::util::opaque_pointer::*;
+use std::cell::Cell;
+use std::sync::atomic::Ordering;
+
+// Each thread counts the bytes it mallocs and frees in a thread local buffer, and only adds them to
+// JULIA_MALLOC_BYTES once the buffer reaches this size, so we do not update a shared counter for
+// every malloc.
+const FLUSH_THRESHOLD: isize = 64 << 10;
+
+struct MallocBuffer {
+    bytes: Cell<isize>,
+}
+
+impl MallocBuffer {
+    fn add(&self, bytes: isize) -> bool {
+        let buffered = self.bytes.get() + bytes;
+        if buffered.abs() < FLUSH_THRESHOLD {
+            self.bytes.set(buffered);
+            return false;
+        }
+        self.bytes.set(0);
+        flush(buffered);
+        true
+    }
+}
+
+impl Drop for MallocBuffer {
+    // Do not lose the bytes of a thread that exits.
+    fn drop(&mut self) {
+        flush(self.bytes.get());
+    }
+}
+
+thread_local! {
+    static MALLOC_BUFFER: MallocBuffer = MallocBuffer { bytes: Cell::new(0) };
+}
+
+fn flush(bytes: isize) {
+    if bytes >= 0 {
+        JULIA_MALLOC_BYTES.fetch_add(bytes as usize, Ordering::SeqCst);
+    } else {
+        JULIA_MALLOC_BYTES.fetch_sub(bytes.unsigned_abs(), Ordering::SeqCst);
+    }
+}
+
+/// Count bytes that are malloc'd (positive) or freed (negative). Returns true if the buffer was
+/// flushed, and the malloc trigger should be checked.
+fn count(bytes: isize) -> bool {
+    // The thread local may be gone if the thread is exiting. Count the bytes directly then.
+    MALLOC_BUFFER
+        .try_with(|buffer| buffer.add(bytes))
+        .unwrap_or_else(|_| {
+            flush(bytes);
+            false
+        })
+}
+
+/// Count the growth of malloc'd memory, and trigger a collection if needed.
+fn count_growth(tls: VMMutatorThread, bytes: usize) {
+    if count(bytes as isize) {
+        crate::gc_trigger::poll_malloc(tls);
+    }
+}
+
+pub fn counted_malloc(tls: VMMutatorThread, size: usize) -> *mut c_void {
+    count_growth(tls, size);
+    let ptr = unsafe { libc::malloc(size) };
+    if ptr.is_null() {
+        count(-(size as isize));
+    }
+    ptr
+}
+
+pub fn counted_calloc(tls: VMMutatorThread, nmemb: usize, size: usize) -> *mut c_void {
+    let bytes = nmemb.saturating_mul(size);
+    count_growth(tls, bytes);
+    let ptr = unsafe { libc::calloc(nmemb, size) };
+    if ptr.is_null() {
+        count(-(bytes as isize));
+    }
+    ptr
+}
+
+pub fn counted_realloc(
+    tls: VMMutatorThread,
+    ptr: *mut c_void,
+    old_size: usize,
+    new_size: usize,
+) -> *mut c_void {
+    if new_size > old_size {
+        count_growth(tls, new_size - old_size);
+    } else {
+        count(-((old_size - new_size) as isize));
+    }
+    let new_ptr = unsafe { libc::realloc(ptr, new_size) };
+    // The old memory is still allocated if realloc fails.
+    if new_ptr.is_null() && new_size != 0 {
+        count(old_size as isize - new_size as isize);
+    }
+    new_ptr
+}
+
+pub fn counted_free(ptr: *mut c_void, size: usize) {
+    unsafe { libc::free(ptr) };
+    count(-(size as isize));
+}
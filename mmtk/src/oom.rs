@@ -0,0 +1,94 @@
+use crate::gc_trigger::GCReason;
+use crate::{JuliaVM, SINGLETON, UPCALLS};
+use mmtk::memory_manager;
+use mmtk::util::alloc::AllocationError;
+use mmtk::util::opaque_pointer::*;
+use mmtk::util::Address;
+use mmtk::vm::ActivePlan;
+use std::cell::Cell;
+use std::sync::Mutex;
+
+/// A callback that is called when the heap is exhausted, before we throw an `OutOfMemoryError`. The
+/// application can free its caches here, so the next GC can reclaim them.
+pub type OOMCallback = extern "C" fn();
+
+lazy_static! {
+    static ref OOM_CALLBACK: Mutex<Option<OOMCallback>> = Mutex::new(None);
+}
+
+thread_local! {
+    // Whether this thread is handling an out of memory error, from the OOM callback until the
+    // allocation is retried. If we run out of memory again, we throw the error right away.
+    static IN_OUT_OF_MEMORY: Cell<bool> = Cell::new(false);
+    // Whether the allocation that failed should be retried. See `alloc_with_retry`.
+    static RETRY_ALLOCATION: Cell<bool> = Cell::new(false);
+}
+
+/// Handle an out of memory error from MMTk.
+/// * `HeapOutOfMemory`: the heap is exhausted. MMTk has already done an emergency GC before
+///   reporting this, so we call the OOM callback, and run a GC to reclaim what the callback frees.
+///   Then we return, and MMTk returns a null address, so `alloc_with_retry` retries the allocation
+///   once. If the heap is exhausted again, we throw an `OutOfMemoryError`.
+/// * `MmapOutOfMemory`: we cannot get memory from the OS. MMTk expects us to abort.
+pub fn out_of_memory(tls: VMThread, err_kind: AllocationError) {
+    match err_kind {
+        AllocationError::HeapOutOfMemory => {
+            let in_out_of_memory = IN_OUT_OF_MEMORY.with(|flag| flag.replace(true));
+            if !in_out_of_memory {
+                if let Some(callback) = *OOM_CALLBACK.lock().unwrap() {
+                    callback();
+                }
+                crate::gc_trigger::request_gc(VMMutatorThread(tls), GCReason::Emergency, true);
+                RETRY_ALLOCATION.with(|flag| flag.set(true));
+                return;
+            }
+            IN_OUT_OF_MEMORY.with(|flag| flag.set(false));
+
+            print_heap_summary("Out of memory: the heap is exhausted");
+            unsafe { ((*UPCALLS).jl_throw_out_of_memory_error)() };
+        }
+        AllocationError::MmapOutOfMemory => {
+            print_heap_summary("Out of memory: failed to map memory from the OS");
+            std::process::abort();
+        }
+    }
+}
+
+/// Allocate with `alloc`, which calls MMTk. If the heap is exhausted, `out_of_memory` runs the OOM
+/// callback and a GC, and MMTk returns a null address. Then we retry the allocation once. If that
+/// fails too, `out_of_memory` throws, so we never return a null address.
+pub fn alloc_with_retry<F: FnMut() -> Address>(mut alloc: F) -> Address {
+    let result = alloc();
+    if !result.is_zero() || !RETRY_ALLOCATION.with(|flag| flag.replace(false)) {
+        return result;
+    }
+    let result = alloc();
+    IN_OUT_OF_MEMORY.with(|flag| flag.set(false));
+    result
+}
+
+fn print_heap_summary(message: &str) {
+    use mmtk::vm::Collection;
+    let (min_heap_size, max_heap_size) = crate::gc_trigger::get_heap_limits();
+    eprintln!("[MMTk] {}", message);
+    eprintln!(
+        "[MMTk] Heap: used = {} bytes, free = {} bytes, total = {} bytes, limits = {}-{} bytes",
+        memory_manager::used_bytes(&SINGLETON),
+        memory_manager::free_bytes(&SINGLETON),
+        memory_manager::total_bytes(&SINGLETON),
+        min_heap_size,
+        max_heap_size
+    );
+    eprintln!(
+        "[MMTk] Malloc'd: {} bytes, mutators: {}, last GC: {:?}",
+        crate::collection::VMCollection::vm_live_bytes(),
+        <crate::active_plan::VMActivePlan as ActivePlan<JuliaVM>>::number_of_mutators(),
+        crate::gc_trigger::current_gc_reason()
+    );
+}
+
+/// Set the callback that is called when the heap is exhausted, or unset it with NULL.
+#[no_mangle]
+pub extern "C" fn mmtk_set_oom_callback(callback: Option<OOMCallback>) {
+    *OOM_CALLBACK.lock().unwrap() = callback;
+}
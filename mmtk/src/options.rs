@@ -0,0 +1,426 @@
+use mmtk::MMTKBuilder;
+use std::fmt;
+use std::sync::RwLock;
+
+/// Environment variable with a list of options, e.g. `MMTK_OPTIONS="threads=4,stress_factor=4096"`.
+pub const OPTIONS_ENV_VAR: &str = "MMTK_OPTIONS";
+/// Environment variable with the path to a config file. The file has one `key = value` per line,
+/// which makes a flat TOML file a valid config file.
+pub const CONFIG_FILE_ENV_VAR: &str = "MMTK_CONFIG_FILE";
+/// Options with this prefix are options of the binding rather than MMTk options.
+pub const JULIA_OPTION_PREFIX: &str = "julia_";
+
+/// MMTk options that cannot be set at run time. The plan is decided when building the binding (see
+/// `mmtk_gc_init`), and would be overwritten.
+const BUILD_TIME_OPTION_KEYS: &[&str] = &["plan"];
+
+/// Options of the binding. They are set in the same way as MMTk options, with the key prefixed with
+/// `julia_`, e.g. `MMTK_OPTIONS="julia_malloc_headroom=20"`.
+#[derive(Clone, Debug)]
+pub struct JuliaOptions {
+    /// The percentage of the container memory limit that is left for malloc'd memory when we size
+    /// the heap from the limit.
+    pub malloc_headroom: usize,
+    /// Trigger a collection when the malloc'd memory has grown by this many bytes since the last
+    /// GC. 0 disables the trigger. The value can have a K/M/G suffix.
+    pub malloc_threshold: usize,
+    /// The CPUs that the GC threads run on, e.g. `0-3,8`. `None` leaves the affinity inherited
+    /// from the thread that initializes MMTk.
+    pub gc_thread_cpus: Option<Vec<usize>>,
+    /// The nice level of the GC threads, from -20 to 19. `None` leaves the nice level inherited.
+    pub gc_thread_nice: Option<i32>,
+    /// Register finalizers with MMTk's finalizer processor, rather than in the thread local lists.
+    pub native_finalizers: bool,
+    /// Run finalizers on a dedicated thread, rather than on the mutator that triggered the GC.
+    pub finalizer_thread: bool,
+    /// Clear soft references in the next GC if the heap occupancy after a GC is at or above this
+    /// percentage of the current heap size. 100 clears them only when the heap is still full after
+    /// a GC, or in an emergency GC.
+    pub soft_ref_threshold: usize,
+    /// Allocate the data buffers of large arrays in the large object space, rather than with
+    /// malloc.
+    pub los_array_buffers: bool,
+}
+
+impl Default for JuliaOptions {
+    fn default() -> Self {
+        JuliaOptions {
+            malloc_headroom: 30,
+            malloc_threshold: 512 << 20,
+            gc_thread_cpus: None,
+            gc_thread_nice: None,
+            native_finalizers: false,
+            finalizer_thread: false,
+            soft_ref_threshold: 90,
+            los_array_buffers: false,
+        }
+    }
+}
+
+impl JuliaOptions {
+    /// Set an option by its name without the prefix. Returns `None` if the key is unknown, and
+    /// `Some(false)` if the value is invalid.
+    fn set(&mut self, key: &str, value: &str) -> Option<bool> {
+        match key {
+            "malloc_headroom" => Some(match value.parse::<usize>() {
+                Ok(percent) if percent < 100 => {
+                    self.malloc_headroom = percent;
+                    true
+                }
+                _ => false,
+            }),
+            "malloc_threshold" => Some(match parse_size(value) {
+                Some(size) => {
+                    self.malloc_threshold = size;
+                    true
+                }
+                None => false,
+            }),
+            "gc_thread_cpus" => Some(match parse_cpu_list(value) {
+                Some(cpus) => {
+                    self.gc_thread_cpus = Some(cpus);
+                    true
+                }
+                None => false,
+            }),
+            "gc_thread_nice" => Some(match value.parse::<i32>() {
+                Ok(nice) if (-20..=19).contains(&nice) => {
+                    self.gc_thread_nice = Some(nice);
+                    true
+                }
+                _ => false,
+            }),
+            "native_finalizers" => Some(match value.parse::<bool>() {
+                Ok(enabled) => {
+                    self.native_finalizers = enabled;
+                    true
+                }
+                Err(_) => false,
+            }),
+            "finalizer_thread" => Some(match value.parse::<bool>() {
+                Ok(enabled) => {
+                    self.finalizer_thread = enabled;
+                    true
+                }
+                Err(_) => false,
+            }),
+            "soft_ref_threshold" => Some(match value.parse::<usize>() {
+                Ok(percent) if (1..=100).contains(&percent) => {
+                    self.soft_ref_threshold = percent;
+                    true
+                }
+                _ => false,
+            }),
+            "los_array_buffers" => Some(match value.parse::<bool>() {
+                Ok(enabled) => {
+                    self.los_array_buffers = enabled;
+                    true
+                }
+                Err(_) => false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref JULIA_OPTIONS: RwLock<JuliaOptions> = RwLock::new(JuliaOptions::default());
+}
+
+/// The result of setting an option from C. See `mmtk_process_option`. An MMTk option that MMTk
+/// does not accept is reported as `InvalidValue`, whether the key or the value is wrong, as MMTk
+/// does not tell them apart.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OptionStatus {
+    Ok = 0,
+    InvalidUtf8 = 1,
+    UnknownKey = 2,
+    InvalidValue = 3,
+}
+
+#[derive(Debug)]
+pub enum OptionError {
+    /// The key or the value is not a valid UTF-8 string.
+    InvalidUtf8 { key: String },
+    /// An entry that is not in the form of `key=value`.
+    Malformed { source: String, entry: String },
+    /// The binding does not know the `julia_` key.
+    UnknownKey { key: String },
+    /// MMTk does not accept the option. MMTk does not tell whether it does not know the key or
+    /// cannot accept the value.
+    Rejected { key: String, value: String },
+    /// The option is decided when building the binding, and cannot be set at run time.
+    BuildTimeOption { key: String },
+    /// The key is known but the value cannot be parsed or is not allowed.
+    InvalidValue { key: String, value: String },
+    /// We cannot read the config file.
+    ConfigFile { path: String, error: std::io::Error },
+}
+
+impl OptionError {
+    pub fn status(&self) -> OptionStatus {
+        match self {
+            OptionError::InvalidUtf8 { .. } => OptionStatus::InvalidUtf8,
+            OptionError::UnknownKey { .. } => OptionStatus::UnknownKey,
+            OptionError::Malformed { .. }
+            | OptionError::BuildTimeOption { .. }
+            | OptionError::Rejected { .. }
+            | OptionError::InvalidValue { .. }
+            | OptionError::ConfigFile { .. } => OptionStatus::InvalidValue,
+        }
+    }
+}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionError::InvalidUtf8 { key } => {
+                write!(
+                    f,
+                    "option {:?} has a key or value that is not valid UTF-8",
+                    key
+                )
+            }
+            OptionError::Malformed { source, entry } => {
+                write!(f, "{}: expected key=value, found {:?}", source, entry)
+            }
+            OptionError::UnknownKey { key } => write!(f, "unknown option {:?}", key),
+            OptionError::Rejected { key, value } => write!(
+                f,
+                "MMTk does not accept {}={:?}: unknown option or invalid value",
+                key, value
+            ),
+            OptionError::BuildTimeOption { key } => {
+                write!(f, "option {:?} is decided when building the binding", key)
+            }
+            OptionError::InvalidValue { key, value } => {
+                write!(f, "invalid value {:?} for option {:?}", value, key)
+            }
+            OptionError::ConfigFile { path, error } => {
+                write!(f, "cannot read config file {:?}: {}", path, error)
+            }
+        }
+    }
+}
+
+/// Set an option on the builder, or a binding option if the key starts with `julia_`.
+pub fn process(builder: &mut MMTKBuilder, key: &str, value: &str) -> Result<(), OptionError> {
+    if let Some(julia_key) = key.strip_prefix(JULIA_OPTION_PREFIX) {
+        return match JULIA_OPTIONS.write().unwrap().set(julia_key, value) {
+            Some(true) => Ok(()),
+            Some(false) => Err(OptionError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            None => Err(OptionError::UnknownKey {
+                key: key.to_string(),
+            }),
+        };
+    }
+
+    if BUILD_TIME_OPTION_KEYS.contains(&key) {
+        return Err(OptionError::BuildTimeOption {
+            key: key.to_string(),
+        });
+    }
+    // MMTk checks the key as well as the value, and returns false rather than panicking on a key
+    // that is not an option.
+    if builder.options.set_from_string(key, value) {
+        Ok(())
+    } else {
+        Err(OptionError::Rejected {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Parse a list of options in the form of `key=value,key=value`, and set them on the builder.
+/// A value may contain commas (e.g. `gc_trigger=DynamicHeapSize:10M,100M`): a segment without
+/// `=` is appended to the value before it. All the errors are returned rather than stopping at the
+/// first one.
+pub fn process_list(builder: &mut MMTKBuilder, source: &str, options: &str) -> Vec<OptionError> {
+    split_list(options)
+        .iter()
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| process_entry(builder, source, entry).err())
+        .collect()
+}
+
+/// Split a list of options into `key=value` entries. A segment without `=` belongs to the value of
+/// the entry before it.
+fn split_list(options: &str) -> Vec<String> {
+    let mut entries: Vec<String> = vec![];
+    for segment in options.split(',') {
+        match entries.last_mut() {
+            Some(last) if !segment.contains('=') => {
+                last.push(',');
+                last.push_str(segment);
+            }
+            _ => entries.push(segment.to_string()),
+        }
+    }
+    entries
+}
+
+/// Read a config file, and set the options on the builder. The file has one `key = value` per
+/// line. Empty lines and lines starting with `#` are ignored, and values can be quoted.
+pub fn process_file(builder: &mut MMTKBuilder, path: &str) -> Vec<OptionError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) => {
+            return vec![OptionError::ConfigFile {
+                path: path.to_string(),
+                error,
+            }]
+        }
+    };
+
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .filter_map(|(i, line)| {
+            let source = format!("{}:{}", path, i + 1);
+            process_entry(builder, &source, line).err()
+        })
+        .collect()
+}
+
+/// Set the options from `MMTK_CONFIG_FILE` and then `MMTK_OPTIONS`, so the environment string
+/// takes precedence over the file. Returns all the errors.
+pub fn process_env(builder: &mut MMTKBuilder) -> Vec<OptionError> {
+    let mut errors = vec![];
+    if let Ok(path) = std::env::var(CONFIG_FILE_ENV_VAR) {
+        errors.extend(process_file(builder, &path));
+    }
+    if let Ok(options) = std::env::var(OPTIONS_ENV_VAR) {
+        errors.extend(process_list(builder, OPTIONS_ENV_VAR, &options));
+    }
+    errors
+}
+
+fn process_entry(builder: &mut MMTKBuilder, source: &str, entry: &str) -> Result<(), OptionError> {
+    let (key, value) = match entry.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => (key.trim(), unquote(value.trim())),
+        _ => {
+            return Err(OptionError::Malformed {
+                source: source.to_string(),
+                entry: entry.to_string(),
+            })
+        }
+    };
+    process(builder, key, value)
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
+/// Parse a size in bytes, with an optional K/M/G suffix, e.g. `512M`.
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (number, shift) = match value.chars().last()?.to_ascii_lowercase() {
+        'k' => (&value[..value.len() - 1], 10),
+        'm' => (&value[..value.len() - 1], 20),
+        'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+/// The number of CPUs in a CPU affinity mask. Affinity is only set on Linux, but we parse the option
+/// the same way elsewhere.
+#[cfg(target_os = "linux")]
+const CPU_SETSIZE: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const CPU_SETSIZE: usize = 1024;
+
+/// Parse a list of CPUs in the format of `taskset -c`, e.g. `0-3,8`. The CPUs must be less than
+/// `CPU_SETSIZE`.
+fn parse_cpu_list(value: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in value.split(',') {
+        let range = range.trim();
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (
+                first.trim().parse::<usize>().ok()?,
+                last.trim().parse::<usize>().ok()?,
+            ),
+            None => {
+                let cpu = range.parse::<usize>().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last || last >= CPU_SETSIZE {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("16k"), Some(16 << 10));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size(" 2G "), Some(2 << 30));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("-1M"), None);
+        assert_eq!(parse_size("10T"), None);
+        assert_eq!(parse_size(&format!("{}G", usize::MAX)), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("3"), Some(vec![3]));
+        assert_eq!(parse_cpu_list("0-3,8"), Some(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list(" 1 - 2 , 5 "), Some(vec![1, 2, 5]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("0-"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+        assert_eq!(parse_cpu_list(""), None);
+        assert_eq!(parse_cpu_list(&CPU_SETSIZE.to_string()), None);
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(
+            split_list("threads=4,stress_factor=4096"),
+            vec!["threads=4", "stress_factor=4096"]
+        );
+        assert_eq!(
+            split_list("gc_trigger=DynamicHeapSize:100M,1G,threads=4"),
+            vec!["gc_trigger=DynamicHeapSize:100M,1G", "threads=4"]
+        );
+        assert_eq!(
+            split_list("julia_gc_thread_cpus=0-3,8"),
+            vec!["julia_gc_thread_cpus=0-3,8"]
+        );
+        assert_eq!(split_list("threads=4,"), vec!["threads=4,"]);
+        assert_eq!(split_list(",threads=4"), vec!["", "threads=4"]);
+    }
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote("\"Immix\""), "Immix");
+        assert_eq!(unquote("'4'"), "4");
+        assert_eq!(unquote("\""), "\"");
+        assert_eq!(unquote("4"), "4");
+    }
+}
@@ -1,5 +1,5 @@
 use crate::julia_types::*;
-use crate::JuliaVM;
+use crate::{JuliaVM, UPCALLS};
 use mmtk::scheduler::ProcessEdgesWork;
 use mmtk::util::opaque_pointer::*;
 use mmtk::util::{Address, ObjectReference};
@@ -25,12 +25,35 @@ impl Finalizable for JuliaFinalizableObject {
     fn keep_alive<E: ProcessEdgesWork>(&mut self, trace: &mut E) {
         self.set_reference(trace.trace_object(self.get_reference()));
         if !self.2 {
-            // not a void pointer
-            trace.trace_object(ObjectReference::from_raw_address(self.1));
+            // not a void pointer. The finalizer may be moved as well.
+            self.1 = trace
+                .trace_object(ObjectReference::from_raw_address(self.1))
+                .to_raw_address();
         }
     }
 }
 
+impl JuliaFinalizableObject {
+    /// The object as it is stored in Julia's finalizer lists: tagged with 1 if the finalizer is a C
+    /// function rather than a Julia function.
+    pub fn tagged_object(&self) -> Address {
+        let object = self.0.to_raw_address();
+        if self.2 {
+            object + 1usize
+        } else {
+            object
+        }
+    }
+
+    /// Run the finalizer on the current thread.
+    pub fn run(&self) {
+        unsafe { ((*UPCALLS).run_finalizer_function)(self.0.to_raw_address(), self.1, self.2) };
+    }
+}
+
+/// References that are registered with MMTk's reference processor (`mmtk_add_soft_candidate` and
+/// `mmtk_add_phantom_candidate`) have the same layout as `WeakRef`, so we access their values as
+/// `mmtk_jl_weakref_t`.
 pub struct VMReferenceGlue {}
 
 impl ReferenceGlue<JuliaVM> for VMReferenceGlue {
@@ -1,6 +1,5 @@
 use crate::edges::JuliaVMEdge;
-use crate::{SINGLETON, UPCALLS};
-use mmtk::memory_manager;
+use crate::UPCALLS;
 use mmtk::scheduler::*;
 use mmtk::util::opaque_pointer::*;
 use mmtk::util::ObjectReference;
@@ -9,9 +8,7 @@ use mmtk::vm::EdgeVisitor;
 use mmtk::vm::ObjectTracerContext;
 use mmtk::vm::RootsWorkFactory;
 use mmtk::vm::Scanning;
-use mmtk::vm::VMBinding;
 use mmtk::Mutator;
-use mmtk::MMTK;
 
 use crate::JuliaVM;
 
@@ -23,6 +20,8 @@ impl Scanning<JuliaVM> for VMScanning {
         mutator: &'static mut Mutator<JuliaVM>,
         mut factory: impl RootsWorkFactory<JuliaVMEdge>,
     ) {
+        crate::gc_stats::MUTATOR_ROOTS.start();
+
         // This allows us to reuse mmtk_scan_gcstack which expectes an EdgeVisitor
         struct EdgeBuffer {
             pub buffer: Vec<JuliaVMEdge>,
@@ -90,6 +89,8 @@ impl Scanning<JuliaVM> for VMScanning {
         for nodes in node_buffer.chunks(CAPACITY_PER_PACKET).map(|c| c.to_vec()) {
             factory.create_process_pinning_roots_work(nodes);
         }
+
+        crate::gc_stats::MUTATOR_ROOTS.end();
     }
 
     fn scan_vm_specific_roots(
@@ -98,9 +99,9 @@ impl Scanning<JuliaVM> for VMScanning {
     ) {
         use crate::edges::RootsWorkClosure;
         let mut roots_closure = RootsWorkClosure::from_roots_work_factory(&mut factory);
-        unsafe {
+        crate::gc_stats::VM_ROOTS.time(|| unsafe {
             ((*UPCALLS).scan_vm_specific_roots)(&mut roots_closure as _);
-        }
+        });
     }
 
     fn scan_object<EV: EdgeVisitor<JuliaVMEdge>>(
@@ -111,12 +112,7 @@ impl Scanning<JuliaVM> for VMScanning {
         process_object(object, edge_visitor);
     }
     fn notify_initial_thread_scan_complete(_partial_scan: bool, _tls: VMWorkerThread) {
-        let sweep_malloced_arrays_work = SweepMallocedArrays::new();
-        memory_manager::add_work_packet(
-            &SINGLETON,
-            WorkBucketStage::Compact,
-            sweep_malloced_arrays_work,
-        );
+        crate::julia_malloced_arrays::schedule_malloced_arrays_sweep();
     }
     fn supports_return_barrier() -> bool {
         unimplemented!()
@@ -127,18 +123,10 @@ impl Scanning<JuliaVM> for VMScanning {
     }
 
     fn process_weak_refs(
-        _worker: &mut GCWorker<JuliaVM>,
+        worker: &mut GCWorker<JuliaVM>,
         tracer_context: impl ObjectTracerContext<JuliaVM>,
     ) -> bool {
-        let single_thread_process_finalizer = ScanFinalizersSingleThreaded { tracer_context };
-        memory_manager::add_work_packet(
-            &SINGLETON,
-            WorkBucketStage::VMRefClosure,
-            single_thread_process_finalizer,
-        );
-
-        // We have pushed work. No need to repeat this method.
-        false
+        crate::weak_refs::process_weak_refs(worker, tracer_context)
     }
 
     fn is_obj_array(o: ObjectReference) -> bool {
@@ -164,34 +152,3 @@ pub fn process_object<EV: EdgeVisitor<JuliaVMEdge>>(object: ObjectReference, clo
         crate::julia_scanning::scan_julia_object(addr, closure);
     }
 }
-
-// Sweep malloced arrays work
-pub struct SweepMallocedArrays {
-    swept: bool,
-}
-
-impl SweepMallocedArrays {
-    pub fn new() -> Self {
-        Self { swept: false }
-    }
-}
-
-impl<VM: VMBinding> GCWork<VM> for SweepMallocedArrays {
-    fn do_work(&mut self, _worker: &mut GCWorker<VM>, _mmtk: &'static MMTK<VM>) {
-        // call sweep malloced arrays from UPCALLS
-        unsafe { ((*UPCALLS).mmtk_sweep_malloced_array)() }
-        self.swept = true;
-    }
-}
-
-pub struct ScanFinalizersSingleThreaded<C: ObjectTracerContext<JuliaVM>> {
-    tracer_context: C,
-}
-
-impl<C: ObjectTracerContext<JuliaVM>> GCWork<JuliaVM> for ScanFinalizersSingleThreaded<C> {
-    fn do_work(&mut self, worker: &mut GCWorker<JuliaVM>, _mmtk: &'static MMTK<JuliaVM>) {
-        self.tracer_context.with_tracer(worker, |tracer| {
-            crate::julia_finalizer::scan_finalizers_in_rust(tracer);
-        });
-    }
-}
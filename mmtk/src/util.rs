@@ -75,6 +75,7 @@ pub(crate) fn get_abi_structs_checksum_rust() -> usize {
         ^ print_sizeof!(mmtk_jl_tls_states_t)
         ^ print_sizeof!(mmtk_jl_thread_heap_t)
         ^ print_sizeof!(mmtk_jl_thread_gc_num_t)
+        ^ print_sizeof!(mmtk_mallocarray_t)
 }
 
 // The functions below allow accessing the values of bitfields without performing a for loop
@@ -0,0 +1,265 @@
+use crate::julia_types::{mmtk_jl_datatype_t, mmtk_jl_weakref_t};
+use crate::reference_glue::jl_nothing;
+use crate::{JuliaVM, SINGLETON};
+use log::*;
+use mmtk::memory_manager;
+use mmtk::scheduler::GCWorker;
+use mmtk::util::{Address, ObjectReference};
+use mmtk::vm::{ObjectTracer, ObjectTracerContext};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// The type of ephemerons, set by Julia with mmtk_set_ephemeron_type. 0 if it is not set.
+static EPHEMERON_TYPE: AtomicUsize = AtomicUsize::new(0);
+
+// The type of soft references, set by Julia with mmtk_set_soft_ref_type. 0 if it is not set.
+static SOFT_REF_TYPE: AtomicUsize = AtomicUsize::new(0);
+
+// Whether we have scanned the finalizers in this GC.
+static FINALIZERS_SCANNED: AtomicBool = AtomicBool::new(false);
+
+// Whether the heap was nearly full after the last GC. See `check_heap_occupancy`.
+static HEAP_NEARLY_FULL: AtomicBool = AtomicBool::new(false);
+// Whether the values of soft references are cleared in this GC. See `prepare_soft_refs`.
+static CLEAR_SOFT_REFS: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // The ephemerons found in this GC whose keys are not known to be reachable yet.
+    static ref EPHEMERONS: Mutex<Vec<ObjectReference>> = Mutex::new(vec![]);
+    // The weak references scanned in this GC.
+    static ref WEAK_REFS: Mutex<Vec<ObjectReference>> = Mutex::new(vec![]);
+    // The soft references scanned in this GC whose values are retained but not traced yet.
+    static ref SOFT_REFS: Mutex<Vec<ObjectReference>> = Mutex::new(vec![]);
+}
+
+/// An ephemeron is an instance of `mutable struct Ephemeron; key; value; end` created by
+/// `jl_mmtk_new_ephemeron`. The ephemeron does not keep its key alive, and it only keeps its value
+/// alive while the key is reachable from elsewhere. So a value that refers to its own key does not
+/// keep the entry alive, unlike a `WeakRef` key.
+#[repr(C)]
+struct Ephemeron {
+    key: Address,
+    value: Address,
+}
+
+pub fn is_ephemeron_type(vt: *const mmtk_jl_datatype_t) -> bool {
+    vt as usize == EPHEMERON_TYPE.load(Ordering::Relaxed)
+}
+
+/// A soft reference is an instance of `mutable struct SoftRef; value; end` created by
+/// `jl_mmtk_new_soft_ref`. It has the same layout as `WeakRef`. Its value is retained, unless the
+/// GC clears soft references (see `prepare_soft_refs`), in which case it is processed like the
+/// value of a `WeakRef`.
+pub fn is_soft_ref_type(vt: *const mmtk_jl_datatype_t) -> bool {
+    vt as usize == SOFT_REF_TYPE.load(Ordering::Relaxed)
+}
+
+/// Called by the GC controller when a GC starts. We clear the values of soft references in an
+/// emergency GC, or if the heap was nearly full after the last GC.
+pub fn prepare_soft_refs() {
+    let clear =
+        SINGLETON.get_plan().is_emergency_collection() || HEAP_NEARLY_FULL.load(Ordering::SeqCst);
+    if clear {
+        debug!("Clearing soft references in this GC");
+    }
+    CLEAR_SOFT_REFS.store(clear, Ordering::SeqCst);
+}
+
+/// Called when a GC is done. The heap is nearly full if the occupancy reaches
+/// `julia_soft_ref_threshold` percent of the current heap size, as decided by the GC trigger. We
+/// check this after a GC, when only the reachable objects are left, rather than when a GC starts,
+/// when the heap is always full.
+pub fn check_heap_occupancy() {
+    let threshold = crate::options::JULIA_OPTIONS
+        .read()
+        .unwrap()
+        .soft_ref_threshold;
+    let used = memory_manager::used_bytes(&SINGLETON);
+    let heap_size = memory_manager::total_bytes(&SINGLETON);
+    let nearly_full = used as u128 * 100 >= heap_size as u128 * threshold as u128;
+    if nearly_full {
+        debug!(
+            "Heap occupancy {}/{} reaches {}%, soft references are cleared in the next GC",
+            used, heap_size, threshold
+        );
+    }
+    HEAP_NEARLY_FULL.store(nearly_full, Ordering::SeqCst);
+}
+
+/// Called when an ephemeron is scanned. We do not trace its key or value here, but in
+/// `process_weak_refs` once we know if the key is reachable.
+pub fn discover_ephemeron(obj: Address) {
+    EPHEMERONS
+        .lock()
+        .unwrap()
+        .push(ObjectReference::from_raw_address(obj));
+}
+
+/// Called when a `SoftRef` is scanned. If we clear soft references in this GC, it is processed like a
+/// `WeakRef`. Otherwise, its value is traced in `process_weak_refs`.
+pub fn discover_soft_ref(obj: Address) {
+    if CLEAR_SOFT_REFS.load(Ordering::Relaxed) {
+        discover_weak_ref(obj);
+    } else {
+        SOFT_REFS
+            .lock()
+            .unwrap()
+            .push(ObjectReference::from_raw_address(obj));
+    }
+}
+
+/// Called when a `WeakRef` is scanned. Its value is not traced, and is processed in
+/// `process_weak_refs` when the reachable objects are known. In a nursery GC, only the young weak
+/// references (and the old ones that were written to since the last GC) are scanned, so they are
+/// the only ones we process. The value of any other weak reference is an old object that a nursery
+/// GC does not collect.
+pub fn discover_weak_ref(obj: Address) {
+    WEAK_REFS
+        .lock()
+        .unwrap()
+        .push(ObjectReference::from_raw_address(obj));
+}
+
+/// This is called by `Scanning::process_weak_refs`, each time after the transitive closure is done,
+/// until it returns false.
+/// 1. We trace the values of the soft references that we retain, and the values of the ephemerons
+///    whose keys are reachable. This may find more soft references and make more keys reachable, so
+///    we repeat this until there is nothing to trace.
+/// 2. We clear the weak references (and the soft references that we clear) whose values are not
+///    reachable, before the finalizers keep more objects alive, like `clear_weak_refs` in gc.c.
+/// 3. We scan the finalizers. Objects with finalizers are kept alive, and may make more keys
+///    reachable, so we go back to 1 for the ephemerons. The weak references found by tracing from
+///    the finalizers are processed at 2 with what is reachable then.
+/// 4. We clear the ephemerons whose keys are not reachable.
+pub fn process_weak_refs<C: ObjectTracerContext<JuliaVM>>(
+    worker: &mut GCWorker<JuliaVM>,
+    tracer_context: C,
+) -> bool {
+    let traced_soft_refs = trace_soft_refs(worker, &tracer_context);
+    if trace_ephemerons(worker, &tracer_context) || traced_soft_refs {
+        return true;
+    }
+
+    update_weak_refs(worker, &tracer_context);
+
+    if !FINALIZERS_SCANNED.swap(true, Ordering::SeqCst) {
+        crate::julia_finalizer::schedule_finalizer_scan(tracer_context);
+        return true;
+    }
+
+    clear_ephemerons();
+    FINALIZERS_SCANNED.store(false, Ordering::SeqCst);
+    false
+}
+
+/// Trace the values of the soft references that we retain. Returns whether anything is traced.
+fn trace_soft_refs<C: ObjectTracerContext<JuliaVM>>(
+    worker: &mut GCWorker<JuliaVM>,
+    tracer_context: &C,
+) -> bool {
+    let mut soft_refs = SOFT_REFS.lock().unwrap();
+    if soft_refs.is_empty() {
+        return false;
+    }
+
+    tracer_context.with_tracer(worker, |tracer| {
+        for soft_ref in soft_refs.drain(..) {
+            let soft_ref =
+                unsafe { &mut *soft_ref.to_raw_address().to_mut_ptr::<mmtk_jl_weakref_t>() };
+            let value = trace(tracer, Address::from_mut_ptr(soft_ref.value));
+            soft_ref.value = value.to_mut_ptr();
+        }
+    });
+    true
+}
+
+/// Trace the values of the ephemerons whose keys are reachable. Returns whether anything is traced.
+fn trace_ephemerons<C: ObjectTracerContext<JuliaVM>>(
+    worker: &mut GCWorker<JuliaVM>,
+    tracer_context: &C,
+) -> bool {
+    let mut ephemerons = EPHEMERONS.lock().unwrap();
+    let len = ephemerons.len();
+    if len == 0 {
+        return false;
+    }
+
+    tracer_context.with_tracer(worker, |tracer| {
+        ephemerons.retain(|ephemeron| {
+            let ephemeron = unsafe { &mut *ephemeron.to_raw_address().to_mut_ptr::<Ephemeron>() };
+            if !is_reachable(ephemeron.key) {
+                return true;
+            }
+            // The key is reachable, so tracing it only gets its new address if it is moved.
+            ephemeron.key = trace(tracer, ephemeron.key);
+            ephemeron.value = trace(tracer, ephemeron.value);
+            false
+        })
+    });
+    ephemerons.len() != len
+}
+
+/// Clear the key and the value of the ephemerons whose keys are not reachable.
+fn clear_ephemerons() {
+    let nothing = unsafe { Address::from_mut_ptr(jl_nothing) };
+    for ephemeron in EPHEMERONS.lock().unwrap().drain(..) {
+        let ephemeron = unsafe { &mut *ephemeron.to_raw_address().to_mut_ptr::<Ephemeron>() };
+        ephemeron.key = nothing;
+        ephemeron.value = nothing;
+    }
+}
+
+/// Set the values of the weak references to `nothing` if they are not reachable, or to their new
+/// addresses if they are moved.
+fn update_weak_refs<C: ObjectTracerContext<JuliaVM>>(
+    worker: &mut GCWorker<JuliaVM>,
+    tracer_context: &C,
+) {
+    let mut weak_refs = WEAK_REFS.lock().unwrap();
+    if weak_refs.is_empty() {
+        return;
+    }
+
+    let nothing = unsafe { Address::from_mut_ptr(jl_nothing) };
+    tracer_context.with_tracer(worker, |tracer| {
+        for weak_ref in weak_refs.drain(..) {
+            let weak_ref =
+                unsafe { &mut *weak_ref.to_raw_address().to_mut_ptr::<mmtk_jl_weakref_t>() };
+            let value = Address::from_mut_ptr(weak_ref.value);
+            let value = if is_reachable(value) {
+                // The value is reachable, so tracing it only gets its new address if it is moved.
+                trace(tracer, value)
+            } else {
+                nothing
+            };
+            weak_ref.value = value.to_mut_ptr();
+        }
+    });
+}
+
+// Objects that are not in MMTk spaces are never collected.
+fn is_reachable(object: Address) -> bool {
+    let object = ObjectReference::from_raw_address(object);
+    !memory_manager::is_in_mmtk_spaces::<JuliaVM>(object) || memory_manager::is_live_object(object)
+}
+
+fn trace<T: ObjectTracer>(tracer: &mut T, object: Address) -> Address {
+    let object = ObjectReference::from_raw_address(object);
+    if memory_manager::is_in_mmtk_spaces::<JuliaVM>(object) {
+        tracer.trace_object(object).to_raw_address()
+    } else {
+        object.to_raw_address()
+    }
+}
+
+/// Set the type of soft references. Julia calls this when it creates the type.
+#[no_mangle]
+pub extern "C" fn mmtk_set_soft_ref_type(vt: Address) {
+    SOFT_REF_TYPE.store(vt.as_usize(), Ordering::Relaxed);
+}
+
+/// Set the type of ephemerons. Julia calls this when it creates the type.
+#[no_mangle]
+pub extern "C" fn mmtk_set_ephemeron_type(vt: Address) {
+    EPHEMERON_TYPE.store(vt.as_usize(), Ordering::Relaxed);
+}